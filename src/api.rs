@@ -0,0 +1,394 @@
+//! An HTTP API for managing the running charge controller.
+//!
+//! - `GET /status` returns the controller's [`Status`];
+//! - `POST /pause` pauses control, given as a [`NewOverride`], leaving the
+//!   vehicles alone until it ends or control is resumed;
+//! - `POST /charge-now` charges at full power for the hours given in a
+//!   [`NewOverride`];
+//! - `POST /resume` clears the override for the vehicle given in a
+//!   [`NewOverride`], or for every vehicle;
+//! - `GET /goals` lists the pending one-off goals;
+//! - `POST /goals` adds a one-off goal, given as a [`NewGoal`];
+//! - `DELETE /goals/<time>` removes the one-off goal at `time`, in RFC 3339
+//!   format as listed, for the vehicle given by a [`GoalVehicle`] query, or
+//!   for every vehicle.
+
+use std::net::SocketAddr;
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+use crate::{
+    config, ControlState, GoalStore, ManualMode, ManualOverride, OneOffGoal, Validate,
+    VehicleStatus,
+};
+
+/// A request to add a one-off goal.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NewGoal {
+    /// The local time to reach the goal by, in the time zone of the vehicle
+    /// the goal is for, or of the top-level charging settings.
+    pub by: NaiveDateTime,
+    pub charge: f64,
+    /// The VIN or display name of the vehicle the goal is for, or `None` for
+    /// every vehicle.
+    #[serde(default)]
+    pub vehicle: Option<String>,
+}
+
+/// The query selecting which goal to remove among those at the same time.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GoalVehicle {
+    /// The vehicle of the goal, as it was added, or `None` for the goal for
+    /// every vehicle.
+    #[serde(default)]
+    pub vehicle: Option<String>,
+}
+
+/// A request to pause control, charge now, or resume control.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NewOverride {
+    /// How long the override lasts, in hours.  Required to charge now; a
+    /// pause without it lasts until control is resumed.
+    #[serde(default)]
+    pub hours: Option<f64>,
+    /// The VIN or display name of the vehicle the override is for, or `None`
+    /// for every vehicle.
+    #[serde(default)]
+    pub vehicle: Option<String>,
+}
+
+/// The state of the running controller.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Status {
+    /// The manual overrides in effect.
+    pub overrides: Vec<ManualOverride>,
+    pub vehicles: Vec<VehicleStatus>,
+}
+
+/// Serve the API on `addr`.
+///
+/// Goal times are resolved using the settings in `vehicles` for the vehicle
+/// they name, and otherwise using `charging`.
+pub async fn serve(
+    addr: SocketAddr,
+    charging: config::Charging,
+    vehicles: Vec<config::VehicleConfig>,
+    goals: GoalStore,
+    control: ControlState,
+) {
+    tracing::info!(%addr, "Serving API");
+    warp::serve(routes(charging, vehicles, goals, control))
+        .run(addr)
+        .await
+}
+
+fn routes(
+    charging: config::Charging,
+    vehicles: Vec<config::VehicleConfig>,
+    goals: GoalStore,
+    control: ControlState,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let with_goals = warp::any().map(move || goals.clone());
+    let with_control = warp::any().map(move || control.clone());
+
+    let status = warp::path!("status")
+        .and(warp::get())
+        .and(with_control.clone())
+        .map(|control: ControlState| {
+            warp::reply::json(&Status {
+                overrides: control.overrides(Utc::now()),
+                vehicles: control.vehicles(),
+            })
+            .into_response()
+        });
+
+    let pause = warp::path!("pause")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_control.clone())
+        .map(|new: NewOverride, control: ControlState| {
+            set_override(&control, ManualMode::Pause, new)
+        });
+
+    let charge_now = warp::path!("charge-now")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_control.clone())
+        .map(|new: NewOverride, control: ControlState| {
+            if new.hours.is_none() {
+                return error(StatusCode::BAD_REQUEST, "hours are required to charge now");
+            }
+            set_override(&control, ManualMode::ChargeNow, new)
+        });
+
+    let resume = warp::path!("resume")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_control)
+        .map(|new: NewOverride, control: ControlState| {
+            if control.clear_override(new.vehicle.as_deref()) {
+                tracing::info!(vehicle = ?new.vehicle, "resumed control");
+                StatusCode::NO_CONTENT.into_response()
+            } else {
+                error(StatusCode::NOT_FOUND, "no override for that vehicle")
+            }
+        });
+
+    let list = warp::path!("goals")
+        .and(warp::get())
+        .and(with_goals.clone())
+        .map(|goals: GoalStore| match goals.active(Utc::now()) {
+            Ok(goals) => warp::reply::json(&goals).into_response(),
+            Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        });
+
+    let add = warp::path!("goals")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_goals.clone())
+        .map(move |new: NewGoal, goals: GoalStore| {
+            let charging = new
+                .vehicle
+                .as_deref()
+                .and_then(|name| vehicles.iter().find(|vehicle| vehicle.is_named(name)))
+                .map_or(&charging, |vehicle| &vehicle.charging);
+            let goal = OneOffGoal {
+                time: charging.local_instant(new.by.date(), new.by.time()),
+                charge: new.charge,
+                vehicle: new.vehicle,
+            };
+            if goal.time <= Utc::now() {
+                return error(StatusCode::BAD_REQUEST, "goal time has already passed");
+            }
+            let goal = match goal.validate() {
+                Ok(goal) => goal,
+                Err(e) => return error(StatusCode::BAD_REQUEST, e),
+            };
+            match goals.add(goal.clone()) {
+                Ok(()) => {
+                    tracing::info!(?goal, "added one-off goal");
+                    warp::reply::with_status(warp::reply::json(&goal), StatusCode::CREATED)
+                        .into_response()
+                }
+                Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        });
+
+    let remove = warp::path("goals")
+        .and(warp::path::param::<DateTime<Utc>>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(warp::query())
+        .and(with_goals)
+        .map(|time, query: GoalVehicle, goals: GoalStore| {
+            match goals.remove(time, query.vehicle.as_deref()) {
+                Ok(true) => {
+                    tracing::info!(?time, vehicle = ?query.vehicle, "removed one-off goal");
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                Ok(false) => error(StatusCode::NOT_FOUND, "no goal at that time"),
+                Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        });
+
+    status
+        .or(pause)
+        .unify()
+        .or(charge_now)
+        .unify()
+        .or(resume)
+        .unify()
+        .or(list)
+        .unify()
+        .or(add)
+        .unify()
+        .or(remove)
+        .unify()
+}
+
+fn set_override(control: &ControlState, mode: ManualMode, new: NewOverride) -> Response {
+    let until = match new.hours {
+        Some(hours) if hours > 0. && hours < 7. * 24. => {
+            Some(Utc::now() + Duration::seconds((hours * 3600.) as i64))
+        }
+        Some(hours) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                format!("hours {} must be in range (0, 168)", hours),
+            )
+        }
+        None => None,
+    };
+    let manual = ManualOverride {
+        mode,
+        until,
+        vehicle: new.vehicle,
+    };
+    tracing::info!(?manual, "set manual override");
+    control.set_override(manual.clone());
+    warp::reply::with_status(warp::reply::json(&manual), StatusCode::CREATED).into_response()
+}
+
+fn error(status: StatusCode, e: impl std::fmt::Display) -> Response {
+    warp::reply::with_status(e.to_string(), status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveTime};
+
+    #[tokio::test]
+    async fn add_list_and_remove_goals() {
+        let charging = config::Charging::default();
+        let blue = config::VehicleConfig {
+            vin: None,
+            display_name: Some("Blue".to_string()),
+            charging: config::Charging {
+                timezone: Some(chrono_tz::Tz::America__New_York),
+                ..config::Charging::default()
+            },
+        };
+        let routes = routes(
+            charging.clone(),
+            vec![blue.clone()],
+            GoalStore::in_memory(),
+            ControlState::new(),
+        );
+
+        let date = Utc::now()
+            .with_timezone(&charging.timezone())
+            .date()
+            .naive_local()
+            + Duration::days(2);
+        let by = date.and_time(NaiveTime::from_hms(7, 0, 0));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/goals")
+            .json(&NewGoal {
+                by,
+                charge: 0.9,
+                vehicle: None,
+            })
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let goal: OneOffGoal = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(goal.time, charging.local_instant(date, by.time()));
+
+        // A goal for a vehicle is in that vehicle's time zone.
+        let response = warp::test::request()
+            .method("POST")
+            .path("/goals")
+            .json(&NewGoal {
+                by: by + Duration::hours(1),
+                charge: 0.8,
+                vehicle: Some("Blue".to_string()),
+            })
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let blue_goal: OneOffGoal = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            blue_goal.time,
+            blue.charging
+                .local_instant(date, by.time() + Duration::hours(1))
+        );
+
+        // Goals in the past or out of range are rejected.
+        for (by, charge) in [(by - Duration::days(3), 0.9), (by, 1.5)] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/goals")
+                .json(&NewGoal {
+                    by,
+                    charge,
+                    vehicle: None,
+                })
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let response = warp::test::request().path("/goals").reply(&routes).await;
+        let goals: Vec<OneOffGoal> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(goals, vec![blue_goal.clone(), goal.clone()]);
+
+        let path = format!("/goals/{}", goal.time.to_rfc3339());
+        for status in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+            let response = warp::test::request()
+                .method("DELETE")
+                .path(&path)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), status);
+        }
+
+        // Removing a vehicle's goal needs the vehicle.
+        let path = format!("/goals/{}", blue_goal.time.to_rfc3339());
+        for (query, status) in [
+            ("", StatusCode::NOT_FOUND),
+            ("?vehicle=Red", StatusCode::NOT_FOUND),
+            ("?vehicle=Blue", StatusCode::NO_CONTENT),
+        ] {
+            let response = warp::test::request()
+                .method("DELETE")
+                .path(&format!("{}{}", path, query))
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn pause_charge_now_and_resume() {
+        let control = ControlState::new();
+        let routes = routes(
+            config::Charging::default(),
+            Vec::new(),
+            GoalStore::in_memory(),
+            control.clone(),
+        );
+        let post = |path: &'static str, new: NewOverride| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .json(&new)
+                .reply(&routes)
+        };
+
+        // Charging now needs a duration.
+        let response = post("/charge-now", NewOverride::default()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = post("/pause", NewOverride::default()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = post(
+            "/charge-now",
+            NewOverride {
+                hours: Some(2.),
+                vehicle: Some("Red".to_string()),
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let charge_now: ManualOverride = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(charge_now.mode, ManualMode::ChargeNow);
+        assert!(charge_now.until.unwrap() > Utc::now() + Duration::minutes(119));
+
+        let response = warp::test::request().path("/status").reply(&routes).await;
+        let status: Status = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(status.overrides.len(), 2);
+        assert!(status.vehicles.is_empty());
+
+        // Resuming every vehicle leaves the override for Red.
+        let response = post("/resume", NewOverride::default()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = post("/resume", NewOverride::default()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(control.overrides(Utc::now()), vec![charge_now]);
+    }
+}
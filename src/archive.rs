@@ -0,0 +1,235 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{prelude::*, BufReader},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Error;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sgip_signal::{Forecast, GridRegion, Moer};
+
+/// An append-only on-disk archive of MOERs and forecasts.
+///
+/// Data is stored as JSON lines, in one directory per region, with MOERs
+/// grouped into a file per month and forecasts (which are much larger) into a
+/// file per day of generation.  Records are never rewritten; if the same MOER
+/// or forecast is archived twice, the later copy wins when reading.
+#[derive(Clone, Debug)]
+pub struct Archive {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MoerRecord {
+    start: DateTime<Utc>,
+    rate: f64,
+    duration_secs: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ForecastRecord {
+    generated_at: DateTime<Utc>,
+    data: BTreeMap<DateTime<Utc>, f64>,
+}
+
+impl Archive {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn moers_path(&self, region: GridRegion, month: NaiveDate) -> PathBuf {
+        self.dir
+            .join(region.to_string())
+            .join(format!("moers-{}.jsonl", month.format("%Y-%m")))
+    }
+
+    fn forecasts_path(&self, region: GridRegion, date: NaiveDate) -> PathBuf {
+        self.dir
+            .join(region.to_string())
+            .join(format!("forecasts-{}.jsonl", date))
+    }
+
+    fn append<T: Serialize>(path: &Path, records: impl Iterator<Item = T>) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut buf = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut buf, &record)?;
+            buf.push(b'\n');
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&buf)?;
+        Ok(())
+    }
+
+    fn read<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Vec<T>, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        BufReader::new(file)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+
+    /// Archive MOERs for the given region.
+    pub fn record_moers(&self, region: GridRegion, moers: &[Moer]) -> Result<(), Error> {
+        let mut by_month = BTreeMap::<_, Vec<_>>::new();
+        for moer in moers {
+            by_month
+                .entry(month_of(moer.start))
+                .or_default()
+                .push(MoerRecord {
+                    start: moer.start,
+                    rate: moer.rate,
+                    duration_secs: moer.duration.num_seconds(),
+                });
+        }
+        for (month, records) in by_month {
+            Self::append(&self.moers_path(region, month), records.into_iter())?;
+        }
+        Ok(())
+    }
+
+    /// Archive forecasts, keyed by region and generation time.
+    pub fn record_forecasts(&self, forecasts: &[Forecast]) -> Result<(), Error> {
+        for forecast in forecasts {
+            Self::append(
+                &self.forecasts_path(forecast.region, forecast.generated_at.date().naive_utc()),
+                std::iter::once(ForecastRecord {
+                    generated_at: forecast.generated_at,
+                    data: forecast.data.clone(),
+                }),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Read the archived MOERs starting in `range`, in time order.
+    pub fn moers(
+        &self,
+        region: GridRegion,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Moer>, Error> {
+        let mut moers = BTreeMap::new();
+        let mut month = month_of(range.start);
+        while month <= range.end.date().naive_utc() {
+            for record in Self::read::<MoerRecord>(&self.moers_path(region, month))? {
+                if range.contains(&record.start) {
+                    moers.insert(
+                        record.start,
+                        Moer {
+                            region,
+                            rate: record.rate,
+                            start: record.start,
+                            duration: Duration::seconds(record.duration_secs),
+                        },
+                    );
+                }
+            }
+            month = next_month(month);
+        }
+        Ok(moers.into_values().collect())
+    }
+
+    /// Read the archived forecasts generated in `range`, in time order.
+    pub fn forecasts(
+        &self,
+        region: GridRegion,
+        range: Range<DateTime<Utc>>,
+    ) -> Result<Vec<Forecast>, Error> {
+        let mut forecasts = BTreeMap::new();
+        let mut date = range.start.date().naive_utc();
+        while date <= range.end.date().naive_utc() {
+            for record in Self::read::<ForecastRecord>(&self.forecasts_path(region, date))? {
+                if range.contains(&record.generated_at) {
+                    forecasts.insert(
+                        record.generated_at,
+                        Forecast {
+                            region,
+                            generated_at: record.generated_at,
+                            data: record.data,
+                        },
+                    );
+                }
+            }
+            date = date.succ();
+        }
+        Ok(forecasts.into_values().collect())
+    }
+}
+
+fn month_of(time: DateTime<Utc>) -> NaiveDate {
+    use chrono::Datelike;
+    let date = time.date().naive_utc();
+    NaiveDate::from_ymd(date.year(), date.month(), 1)
+}
+
+fn next_month(month: NaiveDate) -> NaiveDate {
+    use chrono::Datelike;
+    if month.month() == 12 {
+        NaiveDate::from_ymd(month.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd(month.year(), month.month() + 1, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn archive_round_trip() {
+        let dir = std::env::temp_dir().join(format!("sgip-archive-test-{}", std::process::id()));
+        let archive = Archive::open(&dir).unwrap();
+        let region = GridRegion::CAISO_PGE;
+
+        // Straddle a month boundary.
+        let t0 = Utc.ymd(2021, 2, 28).and_hms(23, 50, 0);
+        let moers = (0..6)
+            .map(|i| Moer {
+                region,
+                rate: 0.1 * i as f64,
+                start: t0 + Duration::minutes(5 * i),
+                duration: Duration::minutes(5),
+            })
+            .collect::<Vec<_>>();
+        archive.record_moers(region, &moers).unwrap();
+        // Duplicates are ignored when reading.
+        archive.record_moers(region, &moers[..2]).unwrap();
+
+        let forecast = Forecast {
+            region,
+            generated_at: t0,
+            data: moers.iter().map(|m| (m.start, m.rate)).collect(),
+        };
+        archive.record_forecasts(&[forecast]).unwrap();
+
+        let read = archive
+            .moers(region, t0..(t0 + Duration::hours(1)))
+            .unwrap();
+        assert_eq!(read.len(), 6);
+        assert_eq!(read[3].start, Utc.ymd(2021, 3, 1).and_hms(0, 5, 0));
+        assert_eq!(read[3].rate, moers[3].rate);
+
+        let forecasts = archive
+            .forecasts(region, t0..(t0 + Duration::minutes(5)))
+            .unwrap();
+        assert_eq!(forecasts.len(), 1);
+        assert_eq!(forecasts[0].data.len(), 6);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
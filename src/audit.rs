@@ -0,0 +1,120 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sgip_signal::GridRegion;
+
+/// One control decision, as recorded in the audit log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    /// The start of the interval the decision is for.
+    pub time: DateTime<Utc>,
+    /// The vehicle or energy site the decision is for.
+    pub unit: String,
+    pub region: GridRegion,
+    /// The MOER the decision used, in kg CO2 / kWh.
+    pub moer: f64,
+    pub moer_start: DateTime<Utc>,
+    /// When the forecast the decision used was generated, if it used one.
+    pub forecast_generated_at: Option<DateTime<Utc>>,
+    /// The emissions limit, as in [`Decision`](crate::Decision).
+    pub emissions_limit: i64,
+    /// The power the unit charges at during the interval, in kW, or a
+    /// negative power for a battery discharging.
+    pub power_kw: f64,
+    /// The length of the interval, in minutes.
+    pub interval_minutes: i64,
+    /// The estimated state of charge, as a fraction.
+    pub soc: f64,
+    pub explanation: String,
+    /// The commands sent to the unit, if any.
+    #[serde(default)]
+    pub commands: Vec<AuditCommand>,
+}
+
+/// A command sent to a vehicle or energy site, with its response.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditCommand {
+    pub command: String,
+    /// `ok`, or the error the command failed with.
+    pub response: String,
+}
+
+impl AuditCommand {
+    pub fn new(command: impl Into<String>, response: &Result<(), Error>) -> Self {
+        Self {
+            command: command.into(),
+            response: match response {
+                Ok(()) => "ok".to_string(),
+                Err(e) => e.to_string(),
+            },
+        }
+    }
+}
+
+/// An append-only log of control decisions, as JSON lines, for demonstrating
+/// signal-responsive operation.
+///
+/// Failing to write the log is reported but doesn't stop the controller.
+#[derive(Clone, Debug, Default)]
+pub struct AuditLog {
+    path: Option<PathBuf>,
+}
+
+impl AuditLog {
+    /// A log that records nothing.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Append to the log at `path`, which is created if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: Some(path.as_ref().to_path_buf()),
+        }
+    }
+
+    pub fn record(&self, entry: &AuditEntry) {
+        if let Err(e) = self.append(entry) {
+            tracing::error!(%e, ?entry, "failed to write audit log");
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // A single write to a file opened for appending can't interleave
+        // with other writers.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&line)?;
+        Ok(())
+    }
+
+    /// Read every entry in the log at `path`.
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<AuditEntry>, Error> {
+        let reader = BufReader::new(fs::File::open(path)?);
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(entries)
+    }
+}
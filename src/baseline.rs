@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+
+use crate::{config, DurationExt};
+
+/// Compares a vehicle's charging emissions with an uncontrolled baseline,
+/// which charges at the full `charge_rate_kw` as soon as the vehicle is
+/// plugged in, until it reaches the charge limit.
+///
+/// The vehicle's energy is matched against the baseline's in the order each
+/// charged it, so the emissions avoided are for the same energy charged at
+/// different times.  Baseline energy the vehicle never matches before it is
+/// unplugged is not counted, and energy beyond what the baseline would have
+/// charged counts the same for both.
+///
+/// The vehicle's energy is only counted once it reports it, and is spread
+/// over the intervals since its last report in proportion to the energy
+/// estimated for each.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Baseline {
+    /// The start of the current interval, with whether the vehicle was
+    /// plugged in, the MOER, and the vehicle's estimated energy then.
+    interval: Option<(DateTime<Utc>, bool, f64, f64)>,
+    /// The estimated energy of each interval since the vehicle last reported
+    /// its energy, with the MOER it was charged at.
+    unreported: Vec<(f64, f64)>,
+    /// The energy the vehicle last reported, with when it did.
+    reported: Option<(DateTime<Utc>, f64)>,
+    /// The baseline's state of charge during the current plug-in session.
+    soc: Option<f64>,
+    /// Energy the baseline charged that the vehicle hasn't yet, in kWh, with
+    /// the MOER it was charged at, oldest first.
+    pending: VecDeque<(f64, f64)>,
+    charged_kwh: f64,
+    emissions_kg: f64,
+    baseline_kg: f64,
+}
+
+impl Baseline {
+    /// Account for the interval since the last update, and start the next one
+    /// at `time`.
+    ///
+    /// The energy estimated for the interval is the increase in
+    /// `charged.estimated_kwh` since the last update, and it is charged at
+    /// the MOER at the interval's start.
+    pub fn update(
+        &mut self,
+        config: &config::Charging,
+        time: DateTime<Utc>,
+        plugged_in: bool,
+        soc: f64,
+        rate: f64,
+        charged: Charged,
+    ) {
+        if let Some((start, was_plugged_in, start_rate, start_kwh)) = self.interval {
+            if was_plugged_in {
+                if let Some(baseline_soc) = self.soc.as_mut() {
+                    let hours = (time - start).num_hours_f64().max(0.);
+                    let room = (config.charge_limit_at(start) - *baseline_soc).max(0.);
+                    let kwh = (config.charge_rate_kw * hours).min(room * config.capacity_kwh);
+                    if kwh > 0. {
+                        *baseline_soc += kwh / config.capacity_kwh;
+                        self.pending.push_back((kwh, start_rate));
+                    }
+                }
+            }
+            self.unreported
+                .push(((charged.estimated_kwh - start_kwh).max(0.), start_rate));
+        }
+
+        // Spread newly reported energy over the intervals it was charged in,
+        // before a new plug-in session discards the baseline's energy.
+        if let Some((reported_at, reported_kwh)) = charged.reported {
+            if self.reported.map(|(at, _)| at) != Some(reported_at) {
+                let previous_kwh = self.reported.map_or(0., |(_, kwh)| kwh);
+                self.spread((reported_kwh - previous_kwh).max(0.), rate);
+                self.reported = Some((reported_at, reported_kwh));
+            }
+        }
+
+        if plugged_in {
+            self.soc.get_or_insert(soc);
+        } else {
+            self.soc = None;
+            self.pending.clear();
+        }
+        self.interval = Some((time, plugged_in, rate, charged.estimated_kwh));
+    }
+
+    /// Charge `kwh` over the unreported intervals, in proportion to their
+    /// estimated energy, or evenly if none was estimated.  Without any
+    /// intervals, the energy is charged at `rate`.
+    fn spread(&mut self, kwh: f64, rate: f64) {
+        let intervals = std::mem::take(&mut self.unreported);
+        if intervals.is_empty() {
+            self.charge(kwh, rate);
+            return;
+        }
+        let estimated_kwh = intervals.iter().map(|(kwh, _)| kwh).sum::<f64>();
+        for (interval_kwh, interval_rate) in &intervals {
+            let share = if estimated_kwh > 0. {
+                interval_kwh / estimated_kwh
+            } else {
+                1. / intervals.len() as f64
+            };
+            self.charge(kwh * share, *interval_rate);
+        }
+    }
+
+    /// Match energy the vehicle charged at `rate` against the baseline's.
+    fn charge(&mut self, mut kwh: f64, rate: f64) {
+        self.charged_kwh += kwh;
+        self.emissions_kg += kwh * rate;
+        while kwh > 0. {
+            let (pending_kwh, pending_rate) = match self.pending.front_mut() {
+                Some(pending) => pending,
+                None => break,
+            };
+            let matched = kwh.min(*pending_kwh);
+            self.baseline_kg += matched * *pending_rate;
+            *pending_kwh -= matched;
+            kwh -= matched;
+            if *pending_kwh <= 0. {
+                self.pending.pop_front();
+            }
+        }
+        self.baseline_kg += kwh * rate;
+    }
+
+    /// The energy the vehicle has charged, in kWh.
+    pub fn charged_kwh(&self) -> f64 {
+        self.charged_kwh
+    }
+
+    /// The emissions from the vehicle's charging, in kg CO2.
+    pub fn emissions_kg(&self) -> f64 {
+        self.emissions_kg
+    }
+
+    /// The emissions from charging the same energy as the baseline would
+    /// have, in kg CO2.
+    pub fn baseline_kg(&self) -> f64 {
+        self.baseline_kg
+    }
+
+    /// The emissions avoided compared to the baseline, in kg CO2.
+    pub fn avoided_kg(&self) -> f64 {
+        self.baseline_kg - self.emissions_kg
+    }
+}
+
+/// The energy a vehicle has charged, as known at an update.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Charged {
+    /// A running estimate of the total energy charged, in kWh.
+    pub estimated_kwh: f64,
+    /// The total energy the vehicle reported charging, in kWh, with when it
+    /// reported it.
+    pub reported: Option<(DateTime<Utc>, f64)>,
+}
+
+impl Charged {
+    /// A total charged energy known exactly at `time`, as in simulation.
+    pub fn exact(time: DateTime<Utc>, kwh: f64) -> Self {
+        Self {
+            estimated_kwh: kwh,
+            reported: Some((time, kwh)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn charging_later_and_cleaner() {
+        let config = config::Charging {
+            charge_rate_kw: 6.,
+            capacity_kwh: 60.,
+            max_charge: 0.6,
+            ..config::Charging::default()
+        };
+        let start = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let mut baseline = Baseline::default();
+        let mut update = |hour: i64, plugged_in: bool, rate: f64, charged_kwh: f64| {
+            let time = start + Duration::hours(hour);
+            let charged = Charged::exact(time, charged_kwh);
+            baseline.update(&config, time, plugged_in, 0.5, rate, charged);
+            (baseline.charged_kwh(), baseline.avoided_kg())
+        };
+
+        // Plugged in at 50%, the baseline charges the 6 kWh to 60% in the
+        // first hour, at 0.8 kg / kWh, while the vehicle waits for the two
+        // hours at 0.2 and charges 3 kWh in each.
+        update(0, true, 0.8, 0.);
+        update(1, true, 0.8, 0.);
+        update(2, true, 0.2, 0.);
+        update(3, true, 0.2, 3.);
+        let (charged, avoided) = update(4, true, 0.5, 6.);
+        assert!((charged - 6.).abs() < 1e-9);
+        assert!((avoided - 6. * 0.6).abs() < 1e-9);
+
+        // Energy past the baseline's limit saves nothing.
+        let (charged, avoided) = update(5, true, 0.5, 9.);
+        assert!((charged - 9.).abs() < 1e-9);
+        assert!((avoided - 3.6).abs() < 1e-9);
+
+        // After unplugging, a new session starts its own baseline, which
+        // the vehicle matches by charging at once.
+        update(6, false, 0.5, 9.);
+        update(7, true, 0.8, 9.);
+        let (charged, avoided) = update(8, true, 0.8, 15.);
+        assert!((charged - 15.).abs() < 1e-9);
+        assert!((avoided - 3.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reported_energy_spread_over_estimates() {
+        let config = config::Charging {
+            charge_rate_kw: 6.,
+            capacity_kwh: 60.,
+            max_charge: 0.6,
+            ..config::Charging::default()
+        };
+        let start = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let at = |hour| start + Duration::hours(hour);
+        let mut baseline = Baseline::default();
+        let mut update = |hour: i64, rate: f64, estimated_kwh: f64, reported_kwh: Option<f64>| {
+            let charged = Charged {
+                estimated_kwh,
+                reported: reported_kwh.map(|kwh| (at(hour), kwh)),
+            };
+            baseline.update(&config, at(hour), true, 0.5, rate, charged);
+            (baseline.charged_kwh(), baseline.avoided_kg())
+        };
+
+        // The vehicle is commanded to charge 3 kWh in each of the two hours
+        // at 0.2, but nothing counts until it reports adding only 4 kWh,
+        // which is spread over those hours.
+        update(0, 0.8, 0., Some(0.));
+        update(1, 0.8, 0., None);
+        update(2, 0.2, 0., None);
+        let (charged, _) = update(3, 0.2, 3., None);
+        assert_eq!(charged, 0.);
+        let (charged, avoided) = update(4, 0.5, 6., Some(4.));
+        assert!((charged - 4.).abs() < 1e-9);
+        assert!((avoided - 4. * 0.6).abs() < 1e-9);
+    }
+}
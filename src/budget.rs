@@ -0,0 +1,75 @@
+/// One vehicle's share of a power budget.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Request {
+    /// The power the vehicle's policy chose, in kW.
+    pub power_kw: f64,
+    /// The lowest nonzero power the vehicle's charger supports, in kW.
+    pub min_power_kw: f64,
+    /// How urgently the vehicle needs charge, from its decision.
+    pub urgency: f64,
+}
+
+/// Share `max_power_kw` between vehicles, returning the power granted to each
+/// request.
+///
+/// Requests are served in order of urgency, each getting as much of what it
+/// asked for as remains.  A vehicle that can't get its charger's minimum
+/// power waits for a later interval, so that vehicles are staggered rather
+/// than all charging slowly.
+pub(crate) fn allocate(max_power_kw: f64, requests: &[Request]) -> Vec<f64> {
+    let mut order = (0..requests.len()).collect::<Vec<_>>();
+    // Serve more urgent vehicles first, breaking ties in config order.
+    order.sort_by(|&a, &b| {
+        requests[b]
+            .urgency
+            .partial_cmp(&requests[a].urgency)
+            .unwrap()
+            .then(a.cmp(&b))
+    });
+
+    let mut granted = vec![0.; requests.len()];
+    let mut remaining_kw = max_power_kw;
+    for i in order {
+        let request = &requests[i];
+        let power_kw = request.power_kw.min(remaining_kw);
+        if power_kw > 0. && power_kw >= request.min_power_kw {
+            granted[i] = power_kw;
+            remaining_kw -= power_kw;
+        }
+    }
+    granted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urgent_vehicles_first() {
+        let request = |power_kw, urgency| Request {
+            power_kw,
+            min_power_kw: 1.2,
+            urgency,
+        };
+
+        // Everything fits.
+        assert_eq!(
+            allocate(20., &[request(8., 0.2), request(8., 0.9)]),
+            vec![8., 8.]
+        );
+
+        // The more urgent vehicle charges at full power, and the other is
+        // throttled to what's left.
+        assert_eq!(
+            allocate(11., &[request(8., 0.2), request(8., 0.9)]),
+            vec![3., 8.]
+        );
+
+        // Less than the minimum power is left, so the other vehicle waits,
+        // and a vehicle that isn't charging takes nothing.
+        assert_eq!(
+            allocate(9., &[request(8., 0.2), request(0., 1.5), request(8., 0.9)]),
+            vec![0., 0., 8.]
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use anyhow::{anyhow, bail, Error};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Utc, Weekday};
+use chrono_tz::Tz;
+use ical::{parser::ical::component::IcalEvent, property::Property, IcalParser};
+
+use crate::{config, intervals::local_instant, OneOffGoal, Validate};
+
+/// How far ahead recurring events are expanded into goals.
+const RECURRENCE_DAYS: i64 = 7;
+
+impl config::Charging {
+    /// Departure goals from the configured calendar that are after `now`.
+    ///
+    /// Recurring events contribute their occurrences over the next week.
+    pub fn calendar_goals(&self, now: DateTime<Utc>) -> Result<Vec<OneOffGoal>, Error> {
+        let calendar = match &self.calendar {
+            Some(calendar) => calendar,
+            None => return Ok(Vec::new()),
+        };
+        let reader = BufReader::new(File::open(&calendar.path)?);
+        let mut goals = departure_goals(
+            reader,
+            &calendar.keyword,
+            self.timezone(),
+            self.max_charge,
+            now + Duration::days(RECURRENCE_DAYS),
+        )?;
+        goals.retain(|goal| goal.time > now);
+        Ok(goals)
+    }
+}
+
+/// Read the goals for events tagged with `keyword`, in time order, expanding
+/// recurring events up to `until`.
+///
+/// Times without a time zone are in `timezone`.  Tagged events that can't be
+/// understood are logged and skipped, so that one bad event doesn't prevent
+/// charging for the rest.
+fn departure_goals<B: BufRead>(
+    reader: B,
+    keyword: &str,
+    timezone: Tz,
+    max_charge: f64,
+    until: DateTime<Utc>,
+) -> Result<Vec<OneOffGoal>, Error> {
+    let keyword = keyword.to_lowercase();
+    let mut goals = Vec::new();
+    for calendar in IcalParser::new(reader) {
+        for event in calendar?.events {
+            let text = ["SUMMARY", "DESCRIPTION"]
+                .iter()
+                .filter_map(|name| property(&event, name)?.value.as_deref())
+                .collect::<Vec<_>>()
+                .join("\n");
+            if !text.to_lowercase().contains(&keyword) {
+                continue;
+            }
+            match departure_goal(&event, &text, timezone, max_charge, until) {
+                Ok(event_goals) => goals.extend(event_goals),
+                Err(e) => tracing::warn!(%e, %text, "skipping calendar event"),
+            }
+        }
+    }
+    goals.sort_by_key(|goal| goal.time);
+    Ok(goals)
+}
+
+fn departure_goal(
+    event: &IcalEvent,
+    text: &str,
+    timezone: Tz,
+    max_charge: f64,
+    until: DateTime<Utc>,
+) -> Result<Vec<OneOffGoal>, Error> {
+    let charge = target_charge(text).unwrap_or(max_charge);
+    occurrences(event, timezone, until)?
+        .into_iter()
+        .map(|time| {
+            OneOffGoal {
+                time,
+                charge,
+                vehicle: None,
+            }
+            .validate()
+        })
+        .collect()
+}
+
+/// The start of every occurrence of `event`, stopping at `until` for
+/// recurring events.
+///
+/// Recurrences are expanded in the event's own time zone, so that they keep
+/// their local time across daylight saving time transitions.  Only daily and
+/// weekly rules are understood.
+fn occurrences(
+    event: &IcalEvent,
+    timezone: Tz,
+    until: DateTime<Utc>,
+) -> Result<Vec<DateTime<Utc>>, Error> {
+    let start = property(event, "DTSTART").ok_or_else(|| anyhow!("event has no start"))?;
+    let (timezone, start) = parse_local(start, timezone)?;
+    let rule = match property(event, "RRULE") {
+        Some(rule) => Recurrence::parse(rule, timezone)?,
+        None => return Ok(vec![local_instant(timezone, start)]),
+    };
+    let until = rule.until.map_or(until, |end| end.min(until));
+
+    let mut times = Vec::new();
+    let mut date = start.date();
+    loop {
+        let time = local_instant(timezone, date.and_time(start.time()));
+        if time > until || rule.count.is_some_and(|count| times.len() >= count) {
+            break;
+        }
+        if rule.includes(start.date(), date) {
+            times.push(time);
+        }
+        date = date.succ();
+    }
+    Ok(times)
+}
+
+/// The parts of an `RRULE` that we understand.
+struct Recurrence {
+    weekly: bool,
+    interval: i64,
+    weekdays: Vec<Weekday>,
+    until: Option<DateTime<Utc>>,
+    count: Option<usize>,
+}
+
+impl Recurrence {
+    fn parse(property: &Property, timezone: Tz) -> Result<Self, Error> {
+        let value = property
+            .value
+            .as_deref()
+            .ok_or_else(|| anyhow!("RRULE has no value"))?;
+        let mut rule = Recurrence {
+            weekly: false,
+            interval: 1,
+            weekdays: Vec::new(),
+            until: None,
+            count: None,
+        };
+        let mut frequency = None;
+        for part in value.split(';') {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow!("malformed RRULE part {}", part))?;
+            match name {
+                "FREQ" => frequency = Some(value),
+                "INTERVAL" => rule.interval = value.parse()?,
+                "COUNT" => rule.count = Some(value.parse()?),
+                "UNTIL" => {
+                    rule.until = Some(match parse_value(value, timezone)? {
+                        // An UNTIL date includes occurrences on that day.
+                        (timezone, until) if !value.contains('T') => {
+                            local_instant(timezone, until + Duration::days(1))
+                                - Duration::seconds(1)
+                        }
+                        (timezone, until) => local_instant(timezone, until),
+                    })
+                }
+                "BYDAY" => {
+                    rule.weekdays = value
+                        .split(',')
+                        .map(|day| weekday(day).ok_or_else(|| anyhow!("unsupported BYDAY {}", day)))
+                        .collect::<Result<_, _>>()?
+                }
+                "WKST" => {}
+                _ => bail!("unsupported RRULE part {}", part),
+            }
+        }
+        rule.weekly = match frequency {
+            Some("DAILY") => false,
+            Some("WEEKLY") => true,
+            Some(frequency) => bail!("unsupported RRULE frequency {}", frequency),
+            None => bail!("RRULE has no frequency"),
+        };
+        if rule.interval < 1 {
+            bail!("RRULE interval must be positive");
+        }
+        Ok(rule)
+    }
+
+    /// Whether the rule for an event starting on `start` has an occurrence on
+    /// `date`.
+    fn includes(&self, start: NaiveDate, date: NaiveDate) -> bool {
+        let on_weekday = if self.weekdays.is_empty() {
+            !self.weekly || date.weekday() == start.weekday()
+        } else {
+            self.weekdays.contains(&date.weekday())
+        };
+        let period = if self.weekly {
+            let monday = |date: NaiveDate| {
+                date - Duration::days(date.weekday().num_days_from_monday().into())
+            };
+            (monday(date) - monday(start)).num_weeks()
+        } else {
+            (date - start).num_days()
+        };
+        on_weekday && period % self.interval == 0
+    }
+}
+
+fn weekday(day: &str) -> Option<Weekday> {
+    Some(match day {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+fn property<'e>(event: &'e IcalEvent, name: &str) -> Option<&'e Property> {
+    event
+        .properties
+        .iter()
+        .find(|property| property.name == name)
+}
+
+/// Parse a `DATE` or `DATE-TIME` property into a local time and the time zone
+/// it is in: UTC, the zone named by its `TZID` parameter, or `timezone` if it
+/// is floating.  Dates are taken to mean the start of the day.
+fn parse_local(property: &Property, timezone: Tz) -> Result<(Tz, NaiveDateTime), Error> {
+    let value = property
+        .value
+        .as_deref()
+        .ok_or_else(|| anyhow!("{} has no value", property.name))?;
+    let tzid = property
+        .params
+        .iter()
+        .flatten()
+        .find(|(name, _)| name == "TZID")
+        .and_then(|(_, values)| values.first());
+    let timezone = match tzid {
+        Some(tzid) => tzid
+            .parse::<Tz>()
+            .map_err(|_| anyhow!("unknown time zone {}", tzid))?,
+        None => timezone,
+    };
+    parse_value(value, timezone)
+}
+
+fn parse_value(value: &str, timezone: Tz) -> Result<(Tz, NaiveDateTime), Error> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        return Ok((
+            Tz::UTC,
+            NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")?,
+        ));
+    }
+    let local = if value.contains('T') {
+        NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")?
+    } else {
+        NaiveDate::parse_from_str(value, "%Y%m%d")?.and_hms(0, 0, 0)
+    };
+    Ok((timezone, local))
+}
+
+/// The first percentage in `text`, as a fraction.
+fn target_charge(text: &str) -> Option<f64> {
+    text.split_whitespace().find_map(|word| {
+        let word = word.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '%');
+        word.strip_suffix('%')?
+            .parse::<f64>()
+            .ok()
+            .map(|percent| percent / 100.)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn tagged_events_become_goals() {
+        let ics = "\
+BEGIN:VCALENDAR
+VERSION:2.0
+BEGIN:VEVENT
+UID:1
+SUMMARY:Tahoe #charge 90%
+DTSTART;TZID=America/Los_Angeles:20210306T070000
+END:VEVENT
+BEGIN:VEVENT
+UID:2
+SUMMARY:Dentist
+DTSTART:20210305T170000Z
+END:VEVENT
+BEGIN:VEVENT
+UID:3
+SUMMARY:Airport run
+DESCRIPTION:#Charge, please.
+DTSTART:20210305T160000Z
+END:VEVENT
+BEGIN:VEVENT
+UID:4
+SUMMARY:Road trip #charge
+DTSTART;VALUE=DATE:20210310
+END:VEVENT
+BEGIN:VEVENT
+UID:5
+SUMMARY:Impossible #charge 150%
+DTSTART:20210305T160000Z
+END:VEVENT
+END:VCALENDAR
+";
+        let goals = departure_goals(
+            ics.as_bytes(),
+            "#charge",
+            Tz::America__New_York,
+            0.8,
+            Utc.ymd(2021, 4, 1).and_hms(0, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(
+            goals,
+            vec![
+                OneOffGoal {
+                    time: Utc.ymd(2021, 3, 5).and_hms(16, 0, 0),
+                    charge: 0.8,
+                    vehicle: None,
+                },
+                OneOffGoal {
+                    time: Utc.ymd(2021, 3, 6).and_hms(15, 0, 0),
+                    charge: 0.9,
+                    vehicle: None,
+                },
+                // All-day events are due at the start of the day, in the
+                // default time zone.
+                OneOffGoal {
+                    time: Utc.ymd(2021, 3, 10).and_hms(5, 0, 0),
+                    charge: 0.8,
+                    vehicle: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn weekly_events_recur() {
+        // Mondays and Wednesdays at 8am Pacific, across the start of daylight
+        // saving time on March 14, for four occurrences.
+        let ics = "\
+BEGIN:VCALENDAR
+VERSION:2.0
+BEGIN:VEVENT
+UID:1
+SUMMARY:Commute #charge 70%
+DTSTART;TZID=America/Los_Angeles:20210308T080000
+RRULE:FREQ=WEEKLY;BYDAY=MO,WE;COUNT=4
+END:VEVENT
+BEGIN:VEVENT
+UID:2
+SUMMARY:Gym #charge
+DTSTART:20210306T170000Z
+RRULE:FREQ=WEEKLY;UNTIL=20210320
+END:VEVENT
+BEGIN:VEVENT
+UID:3
+SUMMARY:Yearly #charge
+DTSTART:20210306T170000Z
+RRULE:FREQ=YEARLY
+END:VEVENT
+END:VCALENDAR
+";
+        let goals = departure_goals(
+            ics.as_bytes(),
+            "#charge",
+            Tz::America__New_York,
+            0.8,
+            Utc.ymd(2021, 3, 16).and_hms(0, 0, 0),
+        )
+        .unwrap();
+        let times = goals.iter().map(|goal| goal.time).collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            vec![
+                Utc.ymd(2021, 3, 6).and_hms(17, 0, 0),
+                Utc.ymd(2021, 3, 8).and_hms(16, 0, 0),
+                Utc.ymd(2021, 3, 10).and_hms(16, 0, 0),
+                Utc.ymd(2021, 3, 13).and_hms(17, 0, 0),
+                Utc.ymd(2021, 3, 15).and_hms(15, 0, 0),
+            ]
+        );
+        let charges = goals.iter().map(|goal| goal.charge).collect::<Vec<_>>();
+        assert_eq!(charges, vec![0.8, 0.7, 0.7, 0.8, 0.7]);
+
+        // Past the horizon, the count and the end date both stop recurrences.
+        let goals = departure_goals(
+            ics.as_bytes(),
+            "#charge",
+            Tz::America__New_York,
+            0.8,
+            Utc.ymd(2021, 4, 1).and_hms(0, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(goals.len(), 7);
+        assert_eq!(goals[6].time, Utc.ymd(2021, 3, 20).and_hms(17, 0, 0));
+    }
+}
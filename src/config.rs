@@ -1,8 +1,13 @@
+use std::{net::SocketAddr, ops::Range, path::PathBuf};
+
 use anyhow::{anyhow, Error};
-use chrono::NaiveTime;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 use sgip_signal::GridRegion;
 
+use crate::OneOffGoal;
+
 pub trait Validate: Sized {
     fn validate(self) -> Result<Self, Error>;
 }
@@ -12,17 +17,334 @@ pub struct Config {
     pub charging: Charging,
     pub tesla_credentials: TeslaCredentials,
     pub sgip_credentials: SgipCredentials,
+    #[serde(default)]
+    pub archive: Option<Archive>,
+    #[serde(default)]
+    pub watttime_credentials: Option<WattTimeCredentials>,
+    #[serde(default)]
+    pub goals: Option<Goals>,
+    #[serde(default)]
+    pub site: Option<Site>,
+    #[serde(default)]
+    pub solar: Option<Solar>,
+    #[serde(default)]
+    pub powerwall: Option<Powerwall>,
+    #[serde(default)]
+    pub audit: Option<Audit>,
+    #[serde(default)]
+    pub api: Option<Api>,
+    /// Vehicles to control, each with its own charging settings.  If empty,
+    /// the first vehicle on the account is controlled using `charging`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vehicles: Vec<VehicleConfig>,
+}
+
+/// Charging settings for one vehicle, selected by VIN or display name.
+///
+/// Emissions data for every vehicle comes from the signal provider selected
+/// in the top-level charging settings, so a vehicle's `signal` and
+/// `watttime_region` must match them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VehicleConfig {
+    #[serde(default)]
+    pub vin: Option<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    pub charging: Charging,
+}
+
+impl VehicleConfig {
+    /// Whether these settings are for the vehicle with `vin` and
+    /// `display_name`.
+    pub fn selects(&self, vin: &str, display_name: &str) -> bool {
+        self.vin.iter().all(|v| v == vin) && self.display_name.iter().all(|d| d == display_name)
+    }
+
+    /// Whether `name` is the VIN or display name these settings select.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.vin.as_deref() == Some(name) || self.display_name.as_deref() == Some(name)
+    }
+
+    /// Check that the vehicle's emissions data comes from the same source
+    /// as the top-level `charging` settings.
+    fn validate_signal(&self, charging: &Charging) -> Result<(), Error> {
+        if self.charging.signal != charging.signal
+            || self.charging.watttime_region != charging.watttime_region
+        {
+            return Err(anyhow!(
+                "vehicle {:?} must use the top-level signal {:?} and watttime_region {:?}",
+                self.vin.as_ref().or(self.display_name.as_ref()),
+                charging.signal,
+                charging.watttime_region
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Charging {
     pub region: GridRegion,
+    /// Local times of day when charging is allowed.  A window whose end is
+    /// before its start runs overnight, ending the next day.
     pub allowed_times: Vec<(NaiveTime, NaiveTime)>,
     pub charge_rate_kw: f64,
     pub capacity_kwh: f64,
     pub max_charge: f64,
     pub flex_charge_hours: i64,
     pub daily_goals: Vec<(NaiveTime, f64)>,
+    #[serde(default)]
+    pub policy: Policy,
+    /// The lowest charging power the charger supports; requests for less are
+    /// rounded to either zero or this value.
+    #[serde(default = "default_min_charge_rate_kw")]
+    pub min_charge_rate_kw: f64,
+    /// The width, in quantiles, of the band over which the quantile policy
+    /// ramps charging power down.  Zero means charging is on/off only.
+    #[serde(default)]
+    pub charge_ramp: f64,
+    /// The charging voltage, used to convert charging power to current.
+    #[serde(default = "default_charge_voltage")]
+    pub charge_voltage: f64,
+    /// How uncertain the estimated state of charge may become before the
+    /// controller wakes the vehicle to check it.
+    #[serde(default = "default_max_soc_uncertainty")]
+    pub max_soc_uncertainty: f64,
+    /// The shortest time to keep charging once started, in minutes, unless
+    /// charging is no longer allowed.
+    #[serde(default)]
+    pub min_run_minutes: i64,
+    /// The shortest time to stay stopped once charging stops, in minutes,
+    /// unless a goal can only be met by charging continuously.
+    #[serde(default)]
+    pub min_rest_minutes: i64,
+    /// A band around the emissions limit, in the limit's units: g CO2 / kWh,
+    /// or under a tariff, thousandths of the combined objective.  Charging
+    /// starts only below the limit minus the band, and stops only above the
+    /// limit plus the band.
+    #[serde(default)]
+    pub emissions_hysteresis: f64,
+    /// The provider of emissions data.
+    #[serde(default)]
+    pub signal: Signal,
+    /// The WattTime region to query, overriding the one covering `region`.
+    /// Outside of California, `region` then only labels the data.
+    #[serde(default)]
+    pub watttime_region: Option<String>,
+    /// The time zone that allowed times and daily goals are given in, if not
+    /// the one for `region`.
+    #[serde(default)]
+    pub timezone: Option<Tz>,
+    /// Schedules replacing the allowed times, daily goals, or max charge on
+    /// particular weekdays or dates.
+    #[serde(default)]
+    pub overrides: Vec<Override>,
+    /// Periods when charging is not allowed, whatever the allowed times.
+    #[serde(default)]
+    pub blackouts: Vec<Blackout>,
+    /// A calendar to read departure goals from.
+    #[serde(default)]
+    pub calendar: Option<Calendar>,
+    /// The electricity tariff, to weigh cost against emissions when
+    /// choosing when to charge.
+    #[serde(default)]
+    pub tariff: Option<Tariff>,
+    /// Pending one-off goals.  These are set at runtime through the
+    /// controller's API rather than in the config file.
+    #[serde(skip)]
+    pub one_off_goals: Vec<OneOffGoal>,
+}
+
+/// Replaces parts of the charging schedule on particular days.
+///
+/// Overrides for specific dates take precedence over overrides for weekdays,
+/// and earlier overrides over later ones.  Settings an override leaves unset
+/// fall through to the next matching override, and then to the defaults.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Override {
+    /// The days of the week the override applies to.
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+    /// The dates the override applies to.
+    #[serde(default)]
+    pub dates: Vec<NaiveDate>,
+    #[serde(default)]
+    pub max_charge: Option<f64>,
+    /// Allowed windows starting on these days.  An empty list means no
+    /// charging windows start on these days.
+    #[serde(default)]
+    pub allowed_times: Option<Vec<(NaiveTime, NaiveTime)>>,
+    #[serde(default)]
+    pub daily_goals: Option<Vec<(NaiveTime, f64)>>,
+}
+
+/// A period when charging is not allowed, such as a holiday, a utility peak
+/// event, or a trip away from home.
+///
+/// `start` and `end` are local dates or date-times.  End dates are inclusive,
+/// and a blackout that starts on a date with no `end` covers just that date.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Blackout {
+    pub start: LocalTime,
+    #[serde(default)]
+    pub end: Option<LocalTime>,
+}
+
+/// Settings for reading departure goals from an iCalendar file.
+///
+/// Events whose summary or description contains `keyword` become goals to
+/// reach the target charge given as a percentage in the event, such as
+/// "Tahoe #charge 90%", by the event's start time.  Events without a
+/// percentage use `max_charge`.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Calendar {
+    /// The `.ics` file to read, which is reread at every interval.
+    pub path: PathBuf,
+    #[serde(default = "default_calendar_keyword")]
+    pub keyword: String,
+}
+
+fn default_calendar_keyword() -> String {
+    "#charge".to_string()
+}
+
+/// A time-of-use electricity tariff.
+///
+/// Policies minimize a weighted combination of the emissions rate, in kg CO2
+/// / kWh, and the price, in $ / kWh: a `cost_weight` of 0 considers only
+/// emissions, and 1 only cost.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Tariff {
+    #[serde(default)]
+    pub cost_weight: f64,
+    /// The seasons of the tariff, which together must cover every month.
+    pub seasons: Vec<Season>,
+}
+
+/// The prices for part of the year.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Season {
+    /// The first and last months of the season, from 1 to 12.  A season
+    /// whose last month is before its first runs over the new year.
+    pub months: (u32, u32),
+    /// The price outside of any period, in $ / kWh.
+    pub price: f64,
+    #[serde(default)]
+    pub periods: Vec<TouPeriod>,
+}
+
+/// A local time of day with its own price, such as a peak period.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TouPeriod {
+    /// The local times the period starts and ends.  A period whose end is
+    /// before its start runs overnight.
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// The days of the week the period applies to, or every day if empty.
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+    /// The price during the period, in $ / kWh.
+    pub price: f64,
+    /// A monthly charge on the peak power drawn during the period, in $ / kW.
+    #[serde(default)]
+    pub demand_charge_per_kw: f64,
+}
+
+/// A local date, or a local date and time.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum LocalTime {
+    DateTime(NaiveDateTime),
+    Date(NaiveDate),
+}
+
+impl Blackout {
+    /// The local times the blackout covers.
+    pub fn local_range(&self) -> Range<NaiveDateTime> {
+        let midnight = NaiveTime::from_hms(0, 0, 0);
+        let start = match self.start {
+            LocalTime::DateTime(start) => start,
+            LocalTime::Date(date) => date.and_time(midnight),
+        };
+        let end = match self.end.unwrap_or(self.start) {
+            LocalTime::DateTime(end) => end,
+            LocalTime::Date(date) => date.succ().and_time(midnight),
+        };
+        start..end
+    }
+}
+
+impl Charging {
+    /// The time zone that allowed times and daily goals are given in.
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+            .unwrap_or_else(|| default_timezone(self.region))
+    }
+}
+
+/// The time zone covering most of each region.
+fn default_timezone(region: GridRegion) -> Tz {
+    match region {
+        GridRegion::WALC => Tz::America__Phoenix,
+        _ => Tz::US__Pacific,
+    }
+}
+
+fn default_min_charge_rate_kw() -> f64 {
+    1.2
+}
+
+fn default_charge_voltage() -> f64 {
+    240.
+}
+
+fn default_max_soc_uncertainty() -> f64 {
+    0.03
+}
+
+/// The built-in charging policy to use.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    /// Charge when the current emissions rate is below a quantile of the
+    /// expected emissions, chosen to meet the most binding goal.
+    #[default]
+    Quantile,
+    /// Plan charging over the whole forecast horizon, choosing the cleanest
+    /// intervals that meet every goal, and follow the plan.
+    Planner,
+}
+
+impl std::fmt::Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Policy::Quantile => "quantile",
+            Policy::Planner => "planner",
+        })
+    }
+}
+
+impl std::str::FromStr for Policy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "quantile" => Ok(Policy::Quantile),
+            "planner" => Ok(Policy::Planner),
+            _ => Err(anyhow!("unknown policy {}", s)),
+        }
+    }
+}
+
+/// The provider of emissions data.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+    /// The SGIP signal, covering California.
+    #[default]
+    Sgip,
+    /// The WattTime API, which also covers regions outside of California.
+    #[serde(rename = "watttime")]
+    WattTime,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -37,6 +359,120 @@ pub struct SgipCredentials {
     pub sgip_password: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct WattTimeCredentials {
+    pub watttime_username: String,
+    pub watttime_password: String,
+}
+
+/// Settings for the on-disk archive of MOERs and forecasts.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Archive {
+    /// The directory to store archived data in.
+    pub path: PathBuf,
+}
+
+/// Limits shared by every vehicle charging at the site.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Site {
+    /// The most power all vehicles together may charge at, in kW, such as
+    /// the capacity of a shared circuit.
+    pub max_power_kw: f64,
+}
+
+/// Rooftop solar to charge from rather than export.
+///
+/// The surplus is the power the site exports plus the power controlled
+/// vehicles are already charging at.  While it is at least `min_export_kw`,
+/// vehicles charge from it whatever the emissions rate.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Solar {
+    /// The SunSpec inverter reporting solar production.
+    pub inverter: ModbusDevice,
+    /// The SunSpec meter at the grid connection, if it is not a model on the
+    /// inverter.
+    #[serde(default)]
+    pub meter: Option<ModbusDevice>,
+    pub min_export_kw: f64,
+}
+
+/// A SunSpec device reached over Modbus TCP.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ModbusDevice {
+    /// The device's address, such as `192.168.1.20:502`.
+    pub address: SocketAddr,
+    #[serde(default = "default_unit_id")]
+    pub unit_id: u8,
+}
+
+fn default_unit_id() -> u8 {
+    1
+}
+
+/// Settings for dispatching a Powerwall by the emissions signal, for the
+/// region in the charging settings.
+///
+/// The battery charges from the grid in the cleanest intervals over the
+/// horizon, and covers the home's load in the dirtiest, by setting its backup
+/// reserve in self-consumption mode.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Powerwall {
+    /// The name of the energy site to control, or the first site on the
+    /// account if unset.
+    #[serde(default)]
+    pub site_name: Option<String>,
+    /// The lowest backup reserve to discharge to, in percent.
+    #[serde(default = "default_min_reserve_percent")]
+    pub min_reserve_percent: u32,
+    /// The power the battery charges from the grid at, in kW.
+    #[serde(default = "default_powerwall_charge_kw")]
+    pub charge_power_kw: f64,
+    /// The typical power the battery discharges at to cover the home's
+    /// load, in kW.
+    #[serde(default = "default_powerwall_discharge_kw")]
+    pub discharge_power_kw: f64,
+    /// How far ahead to compare emissions over, in hours.
+    #[serde(default = "default_powerwall_horizon_hours")]
+    pub horizon_hours: i64,
+}
+
+fn default_min_reserve_percent() -> u32 {
+    20
+}
+
+fn default_powerwall_charge_kw() -> f64 {
+    5.
+}
+
+fn default_powerwall_discharge_kw() -> f64 {
+    2.
+}
+
+fn default_powerwall_horizon_hours() -> i64 {
+    24
+}
+
+/// Settings for persisting one-off goals across controller restarts.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Goals {
+    /// The JSON file to store pending goals in.
+    pub path: PathBuf,
+}
+
+/// Settings for the controller's HTTP API.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Api {
+    /// The address to serve the API on, such as `127.0.0.1:8080`.
+    pub address: SocketAddr,
+}
+
+/// Settings for the audit log of control decisions.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Audit {
+    /// The file to append decisions to, one JSON object per line.
+    pub path: PathBuf,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Simulator {
     pub capacity: f64,
@@ -45,22 +481,45 @@ pub struct Simulator {
 
 impl Validate for Config {
     fn validate(self) -> Result<Self, Error> {
+        for vehicle in &self.vehicles {
+            vehicle.validate_signal(&self.charging)?;
+        }
+        // Only the credentials for the selected signal are needed.
+        let (sgip_credentials, watttime_credentials) = match self.charging.signal {
+            Signal::Sgip => (self.sgip_credentials.validate()?, self.watttime_credentials),
+            Signal::WattTime => (
+                self.sgip_credentials,
+                Some(
+                    self.watttime_credentials
+                        .ok_or_else(|| anyhow!("watttime credentials are required"))?
+                        .validate()?,
+                ),
+            ),
+        };
         Ok(Self {
             charging: self.charging.validate()?,
             tesla_credentials: self.tesla_credentials.validate()?,
-            sgip_credentials: self.sgip_credentials.validate()?,
+            sgip_credentials,
+            archive: self.archive,
+            watttime_credentials,
+            goals: self.goals,
+            site: self.site.map(Validate::validate).transpose()?,
+            solar: self.solar.map(Validate::validate).transpose()?,
+            powerwall: self.powerwall.map(Validate::validate).transpose()?,
+            audit: self.audit,
+            api: self.api,
+            vehicles: self
+                .vehicles
+                .into_iter()
+                .map(Validate::validate)
+                .collect::<Result<_, _>>()?,
         })
     }
 }
 
 impl Validate for Charging {
-    fn validate(self) -> Result<Self, Error> {
-        if !(0.0..1.0).contains(&self.max_charge) {
-            return Err(anyhow!(
-                "max_charge {} must be in range [0.0, 1.0)",
-                self.max_charge
-            ));
-        }
+    fn validate(mut self) -> Result<Self, Error> {
+        validate_max_charge(self.max_charge)?;
         if !(0..(7 * 24)).contains(&self.flex_charge_hours) {
             return Err(anyhow!(
                 "flex_charge_hours {} must be in range [0, {})",
@@ -68,47 +527,285 @@ impl Validate for Charging {
                 7 * 24,
             ));
         }
-        for (_time, charge) in &self.daily_goals {
-            if !(0.0..1.0).contains(charge) {
-                return Err(anyhow!(
-                    "goal charge {} must be in range [0.0, 1.0)",
-                    charge
-                ));
-            }
+        if !(0.0..=self.charge_rate_kw).contains(&self.min_charge_rate_kw) {
+            return Err(anyhow!(
+                "min_charge_rate_kw {} must be in range [0.0, charge_rate_kw]",
+                self.min_charge_rate_kw
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.charge_ramp) {
+            return Err(anyhow!(
+                "charge_ramp {} must be in range [0.0, 1.0]",
+                self.charge_ramp
+            ));
+        }
+        if self.charge_voltage <= 0. {
+            return Err(anyhow!(
+                "charge_voltage {} must be positive",
+                self.charge_voltage
+            ));
+        }
+        if self.min_run_minutes < 0 || self.min_rest_minutes < 0 {
+            return Err(anyhow!(
+                "min_run_minutes {} and min_rest_minutes {} must not be negative",
+                self.min_run_minutes,
+                self.min_rest_minutes
+            ));
+        }
+        if self.emissions_hysteresis < 0. {
+            return Err(anyhow!(
+                "emissions_hysteresis {} must not be negative",
+                self.emissions_hysteresis
+            ));
         }
+        if self.max_soc_uncertainty <= 0. {
+            return Err(anyhow!(
+                "max_soc_uncertainty {} must be positive",
+                self.max_soc_uncertainty
+            ));
+        }
+        validate_daily_goals(&self.daily_goals)?;
 
         if self.allowed_times.is_empty() {
             return Err(anyhow!("must specify at least one allowed charging time"));
         }
+        validate_allowed_times(&mut self.allowed_times)?;
 
-        for (start, end) in &self.allowed_times {
-            if start >= end {
-                return Err(anyhow!(
-                    "specified charging time with start {} >= end {}",
-                    start,
-                    end
-                ));
+        self.overrides = self
+            .overrides
+            .into_iter()
+            .map(Validate::validate)
+            .collect::<Result<_, _>>()?;
+        self.blackouts = self
+            .blackouts
+            .into_iter()
+            .map(Validate::validate)
+            .collect::<Result<_, _>>()?;
+        if let Some(calendar) = &self.calendar {
+            if calendar.keyword.trim().is_empty() {
+                return Err(anyhow!("calendar keyword must not be empty"));
             }
         }
+        self.tariff = self.tariff.map(Validate::validate).transpose()?;
 
-        for ((_, prev_end), (next_start, _)) in self
-            .allowed_times
-            .iter()
-            .zip(self.allowed_times.iter().skip(1))
-        {
-            if prev_end >= next_start {
+        Ok(self)
+    }
+}
+
+impl Validate for Site {
+    fn validate(self) -> Result<Self, Error> {
+        if self.max_power_kw <= 0. {
+            return Err(anyhow!(
+                "site max_power_kw {} must be positive",
+                self.max_power_kw
+            ));
+        }
+        Ok(self)
+    }
+}
+
+impl Validate for Solar {
+    fn validate(self) -> Result<Self, Error> {
+        if self.min_export_kw < 0. {
+            return Err(anyhow!(
+                "solar min_export_kw {} must not be negative",
+                self.min_export_kw
+            ));
+        }
+        Ok(self)
+    }
+}
+
+impl Validate for Powerwall {
+    fn validate(self) -> Result<Self, Error> {
+        if self.min_reserve_percent > 100 {
+            return Err(anyhow!(
+                "powerwall min_reserve_percent {} must be at most 100",
+                self.min_reserve_percent
+            ));
+        }
+        if self.charge_power_kw <= 0. || self.discharge_power_kw <= 0. {
+            return Err(anyhow!(
+                "powerwall charge_power_kw {} and discharge_power_kw {} must be positive",
+                self.charge_power_kw,
+                self.discharge_power_kw
+            ));
+        }
+        if !(1..(7 * 24)).contains(&self.horizon_hours) {
+            return Err(anyhow!(
+                "powerwall horizon_hours {} must be in range [1, {})",
+                self.horizon_hours,
+                7 * 24,
+            ));
+        }
+        Ok(self)
+    }
+}
+
+impl Validate for Tariff {
+    fn validate(self) -> Result<Self, Error> {
+        if !(0.0..=1.0).contains(&self.cost_weight) {
+            return Err(anyhow!(
+                "tariff cost_weight {} must be in range [0.0, 1.0]",
+                self.cost_weight
+            ));
+        }
+        for season in &self.seasons {
+            let (first, last) = season.months;
+            if !(1..=12).contains(&first) || !(1..=12).contains(&last) {
                 return Err(anyhow!(
-                    "charging times must be nonoverlapping and sorted, but prev_end {} >= next_start {}",
-                    prev_end,
-                    next_start,
+                    "season months {:?} must be in range [1, 12]",
+                    season.months
                 ));
             }
+            if season.price < 0. || season.periods.iter().any(|period| period.price < 0.) {
+                return Err(anyhow!(
+                    "prices in season {:?} must not be negative",
+                    season.months
+                ));
+            }
+            if season
+                .periods
+                .iter()
+                .any(|period| period.demand_charge_per_kw < 0.)
+            {
+                return Err(anyhow!("demand charges must not be negative"));
+            }
+        }
+        for month in 1..=12 {
+            match self
+                .seasons
+                .iter()
+                .filter(|season| season.covers(month))
+                .count()
+            {
+                0 => return Err(anyhow!("no tariff season covers month {}", month)),
+                1 => {}
+                _ => return Err(anyhow!("tariff seasons overlap in month {}", month)),
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl Validate for VehicleConfig {
+    fn validate(self) -> Result<Self, Error> {
+        if self.vin.is_none() && self.display_name.is_none() {
+            return Err(anyhow!("vehicles must specify a vin or display_name"));
         }
+        Ok(Self {
+            charging: self.charging.validate()?,
+            ..self
+        })
+    }
+}
+
+impl Validate for Override {
+    fn validate(mut self) -> Result<Self, Error> {
+        if self.weekdays.is_empty() && self.dates.is_empty() {
+            return Err(anyhow!("overrides must specify weekdays or dates"));
+        }
+        if let Some(max_charge) = self.max_charge {
+            validate_max_charge(max_charge)?;
+        }
+        if let Some(daily_goals) = &self.daily_goals {
+            validate_daily_goals(daily_goals)?;
+        }
+        // An override may disallow charging for the whole day.
+        if let Some(allowed_times) = &mut self.allowed_times {
+            validate_allowed_times(allowed_times)?;
+        }
+        Ok(self)
+    }
+}
 
+impl Validate for Blackout {
+    fn validate(self) -> Result<Self, Error> {
+        let range = self.local_range();
+        if range.start >= range.end {
+            return Err(anyhow!(
+                "blackout from {} to {} must end after it starts",
+                range.start,
+                range.end
+            ));
+        }
         Ok(self)
     }
 }
 
+fn validate_max_charge(max_charge: f64) -> Result<(), Error> {
+    if !(0.0..1.0).contains(&max_charge) {
+        return Err(anyhow!(
+            "max_charge {} must be in range [0.0, 1.0)",
+            max_charge
+        ));
+    }
+    Ok(())
+}
+
+fn validate_daily_goals(daily_goals: &[(NaiveTime, f64)]) -> Result<(), Error> {
+    for (_time, charge) in daily_goals {
+        validate_goal_charge(*charge)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_goal_charge(charge: f64) -> Result<(), Error> {
+    if !(0.0..1.0).contains(&charge) {
+        return Err(anyhow!(
+            "goal charge {} must be in range [0.0, 1.0)",
+            charge
+        ));
+    }
+    Ok(())
+}
+
+/// Sort allowed charging windows, checking that they don't overlap.
+fn validate_allowed_times(allowed_times: &mut [(NaiveTime, NaiveTime)]) -> Result<(), Error> {
+    for (start, end) in allowed_times.iter() {
+        if start == end {
+            return Err(anyhow!(
+                "specified charging time with start {} == end {}",
+                start,
+                end
+            ));
+        }
+    }
+    if allowed_times.is_empty() {
+        return Ok(());
+    }
+
+    // Sort the windows, then check that each ends before the next one
+    // starts, including the last one against the first one the next day.
+    allowed_times.sort();
+    let midnight = NaiveTime::from_hms(0, 0, 0);
+    let day = Duration::days(1);
+    let offsets = allowed_times
+        .iter()
+        .map(|(start, end)| {
+            let start = *start - midnight;
+            let end = *end - midnight;
+            (start, if end < start { end + day } else { end })
+        })
+        .collect::<Vec<_>>();
+    let next_starts = offsets
+        .iter()
+        .skip(1)
+        .map(|(start, _)| *start)
+        .chain(std::iter::once(offsets[0].0 + day));
+    for (i, ((_, prev_end), next_start)) in offsets.iter().zip(next_starts).enumerate() {
+        if *prev_end >= next_start {
+            let next = (i + 1) % allowed_times.len();
+            return Err(anyhow!(
+                "charging times must be nonoverlapping, but {:?} overlaps {:?}",
+                allowed_times[i],
+                allowed_times[next],
+            ));
+        }
+    }
+    Ok(())
+}
+
 impl Validate for TeslaCredentials {
     fn validate(self) -> Result<Self, Error> {
         if self == Self::default() {
@@ -131,6 +828,15 @@ impl Validate for SgipCredentials {
     }
 }
 
+impl Validate for WattTimeCredentials {
+    fn validate(self) -> Result<Self, Error> {
+        if self.watttime_username.is_empty() || self.watttime_password.is_empty() {
+            return Err(anyhow!("watttime credentials must not be empty"));
+        }
+        Ok(self)
+    }
+}
+
 impl Default for Charging {
     fn default() -> Charging {
         Charging {
@@ -147,6 +853,22 @@ impl Default for Charging {
                 (NaiveTime::from_hms(8, 0, 0), 0.33),
                 (NaiveTime::from_hms(15, 0, 0), 0.66),
             ],
+            policy: Policy::default(),
+            min_charge_rate_kw: default_min_charge_rate_kw(),
+            charge_ramp: 0.,
+            charge_voltage: default_charge_voltage(),
+            max_soc_uncertainty: default_max_soc_uncertainty(),
+            min_run_minutes: 0,
+            min_rest_minutes: 0,
+            emissions_hysteresis: 0.,
+            signal: Signal::default(),
+            watttime_region: None,
+            timezone: None,
+            overrides: Vec::new(),
+            blackouts: Vec::new(),
+            calendar: None,
+            tariff: None,
+            one_off_goals: Vec::new(),
         }
     }
 }
@@ -185,4 +907,152 @@ mod tests {
 
         assert_eq!(config, config2);
     }
+
+    #[test]
+    fn policy_defaults_to_quantile() {
+        let mut value = toml::Value::try_from(Config::default()).unwrap();
+        value["charging"].as_table_mut().unwrap().remove("policy");
+
+        let config: Config = toml::from_str(&toml::to_string(&value).unwrap()).unwrap();
+
+        assert_eq!(config.charging.policy, Policy::Quantile);
+    }
+
+    #[test]
+    fn omitted_fields_match_defaults() {
+        let mut value = toml::Value::try_from(Config::default()).unwrap();
+        let charging = value["charging"].as_table_mut().unwrap();
+        for field in [
+            "min_charge_rate_kw",
+            "charge_voltage",
+            "max_soc_uncertainty",
+        ] {
+            charging.remove(field);
+        }
+
+        let config: Config = toml::from_str(&toml::to_string(&value).unwrap()).unwrap();
+
+        assert_eq!(config.charging, Charging::default());
+    }
+
+    #[test]
+    fn overnight_allowed_times_are_normalized() {
+        let time = |h| NaiveTime::from_hms(h, 0, 0);
+        let charging = Charging {
+            allowed_times: vec![(time(22), time(7)), (time(12), time(14))],
+            ..Charging::default()
+        };
+        let charging = charging.validate().unwrap();
+        assert_eq!(
+            charging.allowed_times,
+            vec![(time(12), time(14)), (time(22), time(7))]
+        );
+
+        // The overnight window runs into the next day's first window.
+        let overlapping = Charging {
+            allowed_times: vec![(time(6), time(14)), (time(22), time(7))],
+            ..Charging::default()
+        };
+        assert!(overlapping.validate().is_err());
+    }
+
+    #[test]
+    fn blackout_syntax() {
+        #[derive(Deserialize)]
+        struct Blackouts {
+            blackouts: Vec<Blackout>,
+        }
+
+        let Blackouts { blackouts } = toml::from_str(
+            r#"
+            [[blackouts]]
+            start = "2021-12-25"
+
+            [[blackouts]]
+            start = "2021-12-30"
+            end = "2022-01-02"
+
+            [[blackouts]]
+            start = "2021-08-17T16:00:00"
+            end = "2021-08-17T21:00:00"
+            "#,
+        )
+        .unwrap();
+
+        let at = |y, m, d, h| NaiveDate::from_ymd(y, m, d).and_hms(h, 0, 0);
+        assert_eq!(
+            blackouts
+                .iter()
+                .map(Blackout::local_range)
+                .collect::<Vec<_>>(),
+            vec![
+                at(2021, 12, 25, 0)..at(2021, 12, 26, 0),
+                at(2021, 12, 30, 0)..at(2022, 1, 3, 0),
+                at(2021, 8, 17, 16)..at(2021, 8, 17, 21),
+            ]
+        );
+
+        // A time with no end covers nothing.
+        let blackout = Blackout {
+            start: LocalTime::DateTime(at(2021, 8, 17, 16)),
+            end: None,
+        };
+        assert!(blackout.validate().is_err());
+    }
+
+    #[test]
+    fn vehicle_sections() {
+        let config = Config {
+            vehicles: vec![
+                VehicleConfig {
+                    vin: Some("5YJ3E1EA7KF000001".to_string()),
+                    display_name: None,
+                    charging: Charging::default(),
+                },
+                VehicleConfig {
+                    vin: None,
+                    display_name: Some("Blue".to_string()),
+                    charging: Charging {
+                        max_charge: 0.7,
+                        ..Charging::default()
+                    },
+                },
+            ],
+            ..Config::default()
+        };
+
+        let tomled = toml::to_string_pretty(&config).unwrap();
+        let config2: Config = toml::from_str(&tomled).unwrap();
+        assert_eq!(config, config2);
+
+        assert!(config.vehicles[0].selects("5YJ3E1EA7KF000001", "Red"));
+        assert!(!config.vehicles[0].selects("5YJ3E1EA7KF000002", "Blue"));
+        assert!(config.vehicles[1].selects("5YJ3E1EA7KF000002", "Blue"));
+
+        // Every vehicle's emissions data comes from the top-level signal.
+        let mut config = Config {
+            tesla_credentials: TeslaCredentials {
+                tesla_username: "user".to_string(),
+                tesla_password: "password".to_string(),
+            },
+            sgip_credentials: SgipCredentials {
+                sgip_username: "user".to_string(),
+                sgip_password: "password".to_string(),
+            },
+            ..config
+        };
+        assert!(config.clone().validate().is_ok());
+
+        // A vehicle section must say which vehicle it is for.
+        let mut unselected = config.clone();
+        unselected.vehicles.push(VehicleConfig {
+            vin: None,
+            display_name: None,
+            charging: Charging::default(),
+        });
+        assert!(unselected.validate().is_err());
+
+        config.vehicles[1].charging.watttime_region = Some("PJM_DC".to_string());
+        assert!(config.validate().is_err());
+    }
 }
@@ -0,0 +1,147 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{tesla::Vehicle, Slot};
+
+/// What a manual override makes the controller do.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ManualMode {
+    /// Leave the vehicle alone, sending it no commands.
+    Pause,
+    /// Charge at full power, whatever the emissions and allowed times.
+    ChargeNow,
+}
+
+/// A manual override of the controller's decisions.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManualOverride {
+    pub mode: ManualMode,
+    /// When the override ends, or `None` to last until it is cleared.
+    pub until: Option<DateTime<Utc>>,
+    /// The VIN or display name of the vehicle the override is for, or `None`
+    /// for every vehicle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<String>,
+}
+
+/// The decision the controller made for a vehicle.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DecisionStatus {
+    pub power_kw: f64,
+    /// The emissions limit, as in [`Decision`](crate::Decision).
+    pub emissions_limit: i64,
+    pub urgency: f64,
+    pub explanation: String,
+}
+
+/// The goal that most constrains a vehicle's charging.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GoalStatus {
+    pub time: DateTime<Utc>,
+    pub charge: f64,
+    /// The proportion of the remaining allowed charging time needed to meet
+    /// the goal.
+    pub required_proportion: f64,
+}
+
+/// A vehicle's state as of the controller's last interval.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VehicleStatus {
+    pub vehicle: String,
+    pub updated_at: DateTime<Utc>,
+    /// The estimated state of charge, and its error bound.
+    pub soc: f64,
+    pub soc_uncertainty: f64,
+    pub plugged_in: bool,
+    /// The charging power last commanded, in kW.
+    pub power_kw: f64,
+    /// The current MOER, in kg CO2 / kWh, if known.
+    pub moer: Option<f64>,
+    /// The decision for the interval, or `None` if the controller didn't
+    /// decide, such as outside of allowed times or while paused.
+    pub decision: Option<DecisionStatus>,
+    pub goal: Option<GoalStatus>,
+    /// The intervals a plan from now would charge in, whichever policy is
+    /// in use.
+    pub plan: Vec<Slot>,
+    /// The manual override in effect, if any.
+    pub manual: Option<ManualOverride>,
+    /// The emissions avoided compared with uncontrolled charging since the
+    /// controller started, in kg CO2.
+    pub emissions_avoided_kg: f64,
+}
+
+/// The controller's manual overrides and the status of each vehicle, shared
+/// between the controller and its API.
+///
+/// Overrides are dropped once they end, and are not persisted, so a
+/// restarted controller goes back to following its policies.
+#[derive(Clone, Debug, Default)]
+pub struct ControlState {
+    overrides: Arc<Mutex<Vec<ManualOverride>>>,
+    vehicles: Arc<Mutex<Vec<VehicleStatus>>>,
+}
+
+impl ControlState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set an override, replacing any other for the same vehicle.
+    pub fn set_override(&self, manual: ManualOverride) {
+        let mut overrides = self.overrides.lock().unwrap();
+        overrides.retain(|existing| existing.vehicle != manual.vehicle);
+        overrides.push(manual);
+    }
+
+    /// Clear the override for `vehicle`, or for every vehicle if `None`,
+    /// returning whether there was one.
+    pub fn clear_override(&self, vehicle: Option<&str>) -> bool {
+        let mut overrides = self.overrides.lock().unwrap();
+        let len = overrides.len();
+        overrides.retain(|existing| existing.vehicle.as_deref() != vehicle);
+        overrides.len() != len
+    }
+
+    /// The overrides that haven't ended, after dropping those that have.
+    pub fn overrides(&self, now: DateTime<Utc>) -> Vec<ManualOverride> {
+        let mut overrides = self.overrides.lock().unwrap();
+        overrides.retain(|manual| manual.until.iter().all(|until| *until > now));
+        overrides.clone()
+    }
+
+    /// The override in effect for `vehicle`, preferring one for the vehicle
+    /// itself over one for every vehicle.
+    pub(crate) fn override_for(
+        &self,
+        now: DateTime<Utc>,
+        vehicle: &Vehicle,
+    ) -> Option<ManualOverride> {
+        let overrides = self.overrides(now);
+        overrides
+            .iter()
+            .find(|manual| manual.vehicle.iter().any(|name| vehicle.is_named(name)))
+            .or_else(|| overrides.iter().find(|manual| manual.vehicle.is_none()))
+            .cloned()
+    }
+
+    /// Replace the status of `status.vehicle`.
+    pub(crate) fn update(&self, status: VehicleStatus) {
+        let mut vehicles = self.vehicles.lock().unwrap();
+        match vehicles
+            .iter_mut()
+            .find(|existing| existing.vehicle == status.vehicle)
+        {
+            Some(existing) => *existing = status,
+            None => vehicles.push(status),
+        }
+    }
+
+    /// The status of every vehicle the controller has run an interval for.
+    pub fn vehicles(&self) -> Vec<VehicleStatus> {
+        self.vehicles.lock().unwrap().clone()
+    }
+}
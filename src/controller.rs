@@ -1,268 +1,1109 @@
+use std::collections::HashMap;
+
 use anyhow::Error;
 use chrono::{DateTime, Duration, TimeZone, Utc};
-use chrono_tz::US::Pacific;
-use sgip_signal::{Forecast, Moer, SgipSignal};
+use sgip_signal::{Forecast, GridRegion, Moer};
+use tracing::Instrument;
 
 use super::config;
-use crate::{tesla::Vehicle, DurationExt, ForecastExt, History};
+use crate::{
+    baseline::{Baseline, Charged},
+    budget::{self, Request},
+    powerwall::PowerwallController,
+    stability::Stabilizer,
+    sunspec::{SolarMeter, SolarReading},
+    tesla::{ChargeState, Vehicle},
+    AuditCommand, AuditEntry, AuditLog, ChargePolicy, ControlState, Decision, DecisionStatus,
+    GoalStatus, GoalStore, History, ManualMode, ManualOverride, OneOffGoal, Plan, SignalSource,
+    VehicleModel, VehicleStatus,
+};
 
-struct Goal<'c> {
-    time: DateTime<Utc>,
-    charge: f64,
-    config: &'c config::Charging,
+/// Run the charge controller for each `(charging, vehicle)` pair, using the
+/// policy selected in each vehicle's config.
+///
+/// Emissions data is fetched once per region per interval and shared by every
+/// vehicle in the region.  To archive the data the controller fetches, wrap
+/// the `source` in [`Archived`](crate::Archived).  One-off goals are read from
+/// `goals` and the configured calendars at every interval, so they can be
+/// changed while the controller runs.
+///
+/// With a power limit in `site`, the vehicles' decisions are throttled or
+/// staggered so that their combined charging power stays within it, giving
+/// priority to the vehicles whose goals are most urgent.  With solar,
+/// vehicles charge from surplus solar whenever there is enough of it, and
+/// otherwise follow their policies.  A Powerwall is dispatched using the
+/// same emissions data as the vehicles.
+///
+/// Every decision, and the commands sent to act on it, is recorded in
+/// `audit`.  The emissions of the energy each vehicle reports charging are
+/// compared with charging at full power as soon as it is plugged in, and
+/// exported as `charge_emissions_grams` and `baseline_emissions_grams`
+/// counters, with the difference in the `emissions_avoided_kg` gauge.
+///
+/// Each vehicle's status is published to `control` every interval, and
+/// manual overrides set there pause control or charge at full power.
+pub async fn start(
+    vehicles: Vec<(config::Charging, Vehicle)>,
+    site: SiteControl,
+    goals: GoalStore,
+    control: ControlState,
+    audit: AuditLog,
+    source: Box<dyn SignalSource>,
+) -> Result<(), Error> {
+    let vehicles = vehicles
+        .into_iter()
+        .map(|(charging, vehicle)| {
+            let policy = charging.policy.build();
+            (charging, policy, vehicle)
+        })
+        .collect();
+    start_with_policy(vehicles, site, goals, control, audit, source).await
 }
 
-impl<'c> std::fmt::Debug for Goal<'c> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Goal")
-            .field("time", &self.time.with_timezone(&Pacific))
-            .field("charge", &self.charge)
-            .finish()
-    }
-}
+/// Run the charge controller using a caller-supplied [`ChargePolicy`] for
+/// each vehicle.
+pub async fn start_with_policy(
+    vehicles: Vec<(config::Charging, Box<dyn ChargePolicy>, Vehicle)>,
+    site: SiteControl,
+    goals: GoalStore,
+    control: ControlState,
+    audit: AuditLog,
+    mut source: Box<dyn SignalSource>,
+) -> Result<(), Error> {
+    let mut signals = HashMap::<GridRegion, RegionSignal>::new();
+    let mut vehicles = vehicles
+        .into_iter()
+        .map(|(charging, policy, vehicle)| {
+            // Keep history far enough back to see time-shifted charging
+            // windows for every vehicle in the region.
+            let lookback = Duration::days(2) + Duration::hours(charging.flex_charge_hours);
+            let signal = signals
+                .entry(charging.region)
+                .or_insert_with(|| RegionSignal::new(charging.region, lookback));
+            signal.lookback = std::cmp::max(signal.lookback, lookback);
 
-impl<'c> Goal<'c> {
-    fn available_charging_hours(&self, now: DateTime<Utc>) -> f64 {
-        self.config
-            .allowed_times_during(now..self.time)
-            .map(|range| (range.end - range.start).num_hours_f64())
-            .sum()
+            Controlled {
+                model: VehicleModel::new(charging.capacity_kwh),
+                charging,
+                policy,
+                vehicle,
+                stabilizer: Stabilizer::default(),
+                baseline: Baseline::default(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let SiteControl {
+        limit,
+        solar,
+        mut powerwall,
+    } = site;
+    if let Some(powerwall) = &powerwall {
+        let lookback = powerwall.lookback();
+        let signal = signals
+            .entry(powerwall.region())
+            .or_insert_with(|| RegionSignal::new(powerwall.region(), lookback));
+        signal.lookback = std::cmp::max(signal.lookback, lookback);
     }
+    // Connected on first use, and reconnected after any error.
+    let mut solar_meter = None;
 
-    fn required_charging_proportion(&self, now: DateTime<Utc>, present_soc: f64) -> f64 {
-        let charge_kwh = (self.charge - present_soc) * self.config.capacity_kwh;
-        let charge_hours = charge_kwh / self.config.charge_rate_kw;
-        charge_hours / self.available_charging_hours(now)
-    }
-}
+    loop {
+        for signal in signals.values_mut() {
+            signal.fetch_current(source.as_mut()).await?;
+        }
 
-impl config::Charging {
-    /// Returns whether to charge, and the emissions limit used to make that decision.
-    #[tracing::instrument(skip(self, current, history, forecast))]
-    pub fn can_charge(
-        &self,
-        now: DateTime<Utc>,
-        soc: f64,
-        history: &History,
-        current: &Moer,
-        forecast: &Forecast,
-    ) -> (bool, i64) {
-        // Don't charge outside of allowed times.
-        if !self.allowed_at(now) {
-            return (false, -1);
+        let mut steps = Vec::with_capacity(vehicles.len());
+        for controlled in vehicles.iter_mut() {
+            let span = controlled.span();
+            let signal = signals
+                .get_mut(&controlled.charging.region)
+                .expect("every vehicle's region has a signal");
+            let step = controlled
+                .decide(&goals, &control, &audit, signal, source.as_mut())
+                .instrument(span.clone())
+                .await
+                .unwrap_or_else(|e| {
+                    span.in_scope(|| tracing::error!(%e));
+                    None
+                });
+            steps.push(step);
         }
 
-        // Don't charge if the state of charge is bigger than the maximum.
-        if soc >= self.max_charge {
-            return (false, -1);
+        if let Some(solar) = &solar {
+            match read_solar(solar, &mut solar_meter).await {
+                Ok(reading) => charge_from_surplus(solar, reading, &vehicles, &mut steps),
+                Err(e) => {
+                    tracing::error!(%e, "failed to read solar, following policies");
+                    solar_meter = None;
+                }
+            }
         }
 
-        // The config specifies recurring daily goals.  The next recurrence is
-        // either today or tomorrow, so generate both as candidates.
-        let today = now.with_timezone(&Pacific).date();
-        let tomorrow = today.succ();
+        let budgets = match &limit {
+            Some(site) => {
+                let requests = vehicles
+                    .iter()
+                    .zip(&steps)
+                    .map(|(controlled, step)| {
+                        site_request(&controlled.charging, &controlled.model, step.as_ref())
+                    })
+                    .collect::<Vec<_>>();
+                budget::allocate(site.max_power_kw, &requests)
+            }
+            None => vec![f64::INFINITY; vehicles.len()],
+        };
 
-        let today_goals = self.daily_goals.iter().map(|(time, charge)| Goal {
-            time: today.and_time(*time).unwrap().with_timezone(&Utc),
-            charge: *charge,
-            config: &self,
-        });
-        let tomorrow_goals = self.daily_goals.iter().map(|(time, charge)| Goal {
-            time: tomorrow.and_time(*time).unwrap().with_timezone(&Utc),
-            charge: *charge,
-            config: &self,
-        });
+        for ((controlled, step), max_power_kw) in vehicles.iter_mut().zip(steps).zip(budgets) {
+            let step = match step {
+                Some(step) => step,
+                None => continue,
+            };
+            let span = controlled.span();
+            let signal = &signals[&controlled.charging.region];
+            if let Err(e) = controlled
+                .act(step, max_power_kw, signal, &control, &audit)
+                .instrument(span.clone())
+                .await
+            {
+                span.in_scope(|| tracing::error!(%e));
+            }
+        }
 
-        // Flex charging: aim to complete the rest of the charging a fixed
-        // time from now, whatever now is.
-        let flex_goal = Goal {
-            time: now + Duration::hours(self.flex_charge_hours),
-            charge: self.max_charge,
-            config: &self,
-        };
+        if let Some(powerwall) = &mut powerwall {
+            let signal = signals
+                .get_mut(&powerwall.region())
+                .expect("the Powerwall's region has a signal");
+            if let Err(e) = powerwall.step(signal, source.as_mut(), &audit).await {
+                tracing::error!(%e, "failed to dispatch Powerwall");
+            }
+        }
 
-        let mut goals = std::iter::once(flex_goal)
-            .chain(today_goals)
-            .chain(tomorrow_goals)
-            .filter(|goal| goal.time > now)
-            .filter(|goal| goal.charge > soc)
-            .collect::<Vec<Goal>>();
+        sleep_until_next_window().await;
+    }
+}
 
-        // Choose the goal with the largest required charging proportion.
-        goals.sort_by(|a, b| {
-            let a_req = a.required_charging_proportion(now, soc);
-            let b_req = b.required_charging_proportion(now, soc);
+/// Limits and devices shared by every vehicle charging at the site.
+#[derive(Default)]
+pub struct SiteControl {
+    /// The limit on the vehicles' combined charging power.
+    pub limit: Option<config::Site>,
+    /// Rooftop solar to charge from rather than export.
+    pub solar: Option<config::Solar>,
+    /// A Powerwall to dispatch by the emissions signal.
+    pub powerwall: Option<PowerwallController>,
+}
 
-            a_req.partial_cmp(&b_req).unwrap()
-        });
-        tracing::info!(?goals);
-        let goal = goals.pop().expect("must have at least one goal");
-        tracing::info!(?goal, "selected goal");
-
-        let available_charging_hours = goal.available_charging_hours(now);
-        let required_charging_proportion = goal.required_charging_proportion(now, soc);
-
-        let lookahead = self
-            .allowed_times_during(now..goal.time)
-            .collect::<Vec<_>>();
-
-        // The SGIP forecasts often get the curve right but offset up or down,
-        // which biases the forecast emissions data, so combine the forecast
-        // data for the allowed charging windows with the actual data for the
-        // same windows on previous days.
-        let lookback = [Duration::days(1), Duration::days(2)]
+/// Sleep until the start of the next 5-minute interval.
+async fn sleep_until_next_window() {
+    let now = Utc::now().timestamp();
+    let next = Utc.timestamp(now + (300 - now.rem_euclid(300)), 0);
+    tokio::time::sleep((next - Utc::now()).to_std().unwrap()).await;
+}
+
+/// The share of the site's power limit a vehicle asks for.
+fn site_request(charging: &config::Charging, model: &VehicleModel, step: Option<&Step>) -> Request {
+    match step {
+        // A vehicle that isn't plugged in draws nothing, whatever its policy
+        // decided.
+        Some(_) if !model.plugged_in() => Request {
+            power_kw: 0.,
+            min_power_kw: 0.,
+            urgency: 0.,
+        },
+        Some(step) => Request {
+            power_kw: step.decision.power_kw,
+            min_power_kw: charging.min_charge_rate_kw,
+            urgency: step.decision.urgency,
+        },
+        // Without a decision, the vehicle keeps doing what it was doing, so
+        // reserve its power first.
+        None => Request {
+            power_kw: model.power_kw(),
+            min_power_kw: 0.,
+            urgency: f64::INFINITY,
+        },
+    }
+}
+
+/// Read the site's solar, connecting to its devices if needed.
+async fn read_solar(
+    solar: &config::Solar,
+    meter: &mut Option<SolarMeter>,
+) -> Result<SolarReading, Error> {
+    if meter.is_none() {
+        tracing::info!(inverter = %solar.inverter.address, "Connecting to solar");
+        *meter = Some(SolarMeter::connect(solar).await?);
+    }
+    let reading = meter.as_mut().unwrap().read().await?;
+    tracing::info!(?reading, "Read solar");
+    metrics::gauge!("solar_production_kw", reading.production_kw);
+    metrics::gauge!("solar_export_kw", reading.export_kw);
+    Ok(reading)
+}
+
+/// Replace decisions with charging from surplus solar, where that charges
+/// faster.  The surplus is shared between vehicles as a power budget, by
+/// urgency.
+fn charge_from_surplus(
+    solar: &config::Solar,
+    reading: SolarReading,
+    vehicles: &[Controlled],
+    steps: &mut [Option<Step>],
+) {
+    // Vehicles charging now are using surplus that would otherwise be
+    // exported.
+    let surplus_kw = reading.export_kw
+        + vehicles
             .iter()
-            .flat_map(|offset| {
-                lookahead
-                    .iter()
-                    .map(move |std::ops::Range { start, end }| (*start - *offset)..(*end - *offset))
-            })
-            .collect::<Vec<_>>();
-        tracing::debug!(?lookahead, ?lookback);
+            .zip(steps.iter())
+            .filter(|(_, step)| step.is_some())
+            .map(|(controlled, _)| controlled.model.power_kw())
+            .sum::<f64>();
+    metrics::gauge!("solar_surplus_kw", surplus_kw);
+    if surplus_kw < solar.min_export_kw {
+        return;
+    }
+
+    let requests = vehicles
+        .iter()
+        .zip(steps.iter())
+        .map(|(controlled, step)| match step {
+            // Only vehicles that are plugged in can use the surplus.
+            Some(step)
+                if controlled.model.plugged_in()
+                    && controlled.model.soc() < controlled.charging.charge_limit_at(step.now) =>
+            {
+                Request {
+                    power_kw: controlled.charging.charge_rate_kw,
+                    min_power_kw: controlled.charging.min_charge_rate_kw,
+                    urgency: step.decision.urgency,
+                }
+            }
+            _ => Request {
+                power_kw: 0.,
+                min_power_kw: 0.,
+                urgency: 0.,
+            },
+        })
+        .collect::<Vec<_>>();
+    let granted = budget::allocate(surplus_kw, &requests);
+
+    for (step, power_kw) in steps.iter_mut().zip(granted) {
+        if let Some(step) = step {
+            if power_kw > step.decision.power_kw {
+                let surplus = Surplus {
+                    power_kw,
+                    surplus_kw,
+                };
+                surplus.apply(&mut step.decision);
+                step.surplus = Some(surplus);
+            }
+        }
+    }
+}
+
+/// Surplus solar power granted to a vehicle.
+#[derive(Clone, Copy, Debug)]
+struct Surplus {
+    power_kw: f64,
+    /// The whole surplus, shared by every vehicle.
+    surplus_kw: f64,
+}
 
-        let mut emissions = history.histogram_over(lookback) + forecast.histogram_over(lookahead);
+impl Surplus {
+    /// Charges at the granted power if the decision was for less.
+    fn apply(&self, decision: &mut Decision) {
+        if self.power_kw > decision.power_kw {
+            decision.explanation = format!(
+                "charging at {:.2} kW from {:.2} kW of surplus solar (policy decided: {})",
+                self.power_kw, self.surplus_kw, decision.explanation
+            );
+            decision.power_kw = self.power_kw;
+            decision.emissions_limit = -1;
+        }
+    }
+}
 
-        // Ensure that the current emissions rate is included in the histogram,
-        // so that the 100th-percentile value of the histogram is >= the current
-        // rate.  This means that if charge_time_proportion >= 1, we're sure to
-        // charge continuously until the charge target is met.
-        let current_rate = (current.rate * 1000.) as u64;
-        emissions += current_rate;
+/// The emissions data for one region, shared by every vehicle in it.
+pub(crate) struct RegionSignal {
+    lookback: Duration,
+    history: History,
+    /// The current MOER, fetched at the start of every interval.
+    current: Option<Moer>,
+    /// The forecast, fetched at most once per interval, and only if some
+    /// vehicle is allowed to charge.
+    forecast: Option<Forecast>,
+}
 
-        let emissions_limit = emissions.value_at_quantile(required_charging_proportion);
-        let can_charge = current_rate <= emissions_limit;
+impl RegionSignal {
+    /// Emissions data for `region`, keeping history back to `lookback` ago.
+    pub fn new(region: GridRegion, lookback: Duration) -> Self {
+        Self {
+            lookback,
+            history: History::new(region, Vec::new()),
+            current: None,
+            forecast: None,
+        }
+    }
 
-        // Finally, do tracing and logging of the factors for the decision.
+    /// Fetch the current MOER, starting a new interval.
+    pub async fn fetch_current(&mut self, source: &mut dyn SignalSource) -> Result<(), Error> {
+        let region = self.history.region();
+        tracing::info!(?region, "Fetching current MOER");
+        let current = source.moer(region).await?;
+        self.history.insert(current.clone());
+        self.current = Some(current);
+        self.forecast = None;
+        Ok(())
+    }
 
-        tracing::info!(
-            now = ?now.with_timezone(&Pacific),
-            goal.time = ?now.with_timezone(&Pacific),
-            ?soc,
-            ?goal.charge,
-            ?required_charging_proportion,
-            ?emissions_limit,
-            ?current_rate,
-            ?can_charge,
-        );
+    /// Fetch the forecast and backfill the history back to the lookback
+    /// before `now`, if not already done in this interval.
+    pub async fn prepare(
+        &mut self,
+        now: DateTime<Utc>,
+        source: &mut dyn SignalSource,
+    ) -> Result<(), Error> {
+        let current = self
+            .current
+            .as_ref()
+            .expect("current MOER is fetched first");
+        if self.forecast.is_some() {
+            return Ok(());
+        }
+
+        tracing::info!("Fetching forecast");
+        let forecast = source.forecast(current.region).await?;
 
-        metrics::gauge!("vehicle_soc", soc);
-        metrics::gauge!("charge_available_hours", available_charging_hours);
-        metrics::gauge!("charge_required_proportion", required_charging_proportion);
-        metrics::gauge!("charge_goal", goal.charge);
-
-        let g_to_kg = |g: u64| (g as f64) / 1000.;
-        let emissions_quantile = |q: f64| g_to_kg(emissions.value_at_quantile(q));
-        metrics::gauge!("charge_emissions_min", emissions_quantile(0.00));
-        metrics::gauge!("charge_emissions_q10", emissions_quantile(0.10));
-        metrics::gauge!("charge_emissions_q25", emissions_quantile(0.25));
-        metrics::gauge!("charge_emissions_q50", emissions_quantile(0.50));
-        metrics::gauge!("charge_emissions_q75", emissions_quantile(0.75));
-        metrics::gauge!("charge_emissions_q90", emissions_quantile(0.90));
-        metrics::gauge!("charge_emissions_max", emissions_quantile(1.00));
-        metrics::gauge!("charge_emissions_limit", g_to_kg(emissions_limit));
-        metrics::gauge!("emissions_current", g_to_kg(current_rate));
-
-        (can_charge, emissions_limit as i64)
+        // Download only what the controller hasn't already observed.
+        let lookback_start = now - self.lookback;
+        self.history.prune(lookback_start);
+        for gap in self.history.gaps(lookback_start..current.start) {
+            tracing::info!(?gap, "Backfilling MOER history");
+            let moers = source
+                .historic_moers(current.region, gap.start, Some(gap.end))
+                .await?;
+            self.history.backfill(gap, moers);
+        }
+
+        self.forecast = Some(forecast);
+        Ok(())
+    }
+
+    /// The history, current MOER, and forecast, once prepared.
+    pub fn data(&self) -> (&History, &Moer, &Forecast) {
+        (
+            &self.history,
+            self.current
+                .as_ref()
+                .expect("current MOER is fetched first"),
+            self.forecast.as_ref().expect("forecast is prepared first"),
+        )
     }
 }
 
-pub async fn start(
+/// A vehicle under charge control.
+struct Controlled {
     charging: config::Charging,
-    mut sgip: SgipSignal,
+    policy: Box<dyn ChargePolicy>,
     vehicle: Vehicle,
-) -> Result<(), Error> {
-    let next_window = || {
-        let now = Utc::now().timestamp();
-        // Next 5-minute interval.
-        let next = now + (300 - now.rem_euclid(300));
-        Utc.timestamp(next, 0)
-    };
+    model: VehicleModel,
+    stabilizer: Stabilizer,
+    baseline: Baseline,
+}
 
-    // TODO: correctly model vehicle charge state to avoid having to wake the
-    // car every 5 minutes. currently this is just used to stop charging in
-    // disallowed hours
-    let mut is_charging = false;
+/// A vehicle's decision for the current interval, before it is acted on.
+struct Step {
+    now: DateTime<Utc>,
+    decision: Decision,
+    charge_state: Option<ChargeState>,
+    /// A manual override to charge now, if one is in effect.
+    manual: Option<ManualOverride>,
+    /// Surplus solar power granted on top of the decision.
+    surplus: Option<Surplus>,
+}
 
-    loop {
-        tracing::info!("Fetching current MOER");
-        let current = sgip.moer(charging.region).await?;
+impl Controlled {
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("vehicle", name = %self.vehicle.name())
+    }
 
-        if charging.allowed_at(Utc::now()) {
-            match charge_step(&charging, &current, &mut sgip, &vehicle).await {
-                Ok(charging) => is_charging = charging,
-                Err(e) => tracing::error!(%e),
-            }
-        } else {
+    /// Decides how to charge during the current interval, returning `None` if
+    /// the vehicle isn't allowed to charge or control is paused.
+    ///
+    /// The vehicle is only woken when the model's state of charge estimate is
+    /// too uncertain.
+    async fn decide(
+        &mut self,
+        goals: &GoalStore,
+        control: &ControlState,
+        audit: &AuditLog,
+        signal: &mut RegionSignal,
+        source: &mut dyn SignalSource,
+    ) -> Result<Option<Step>, Error> {
+        match self.one_off_goals(goals) {
+            Ok(goals) => self.charging.one_off_goals = goals,
+            Err(e) => tracing::error!(%e, "failed to update one-off goals"),
+        }
+        let label = self.vehicle.name().to_string();
+
+        let manual = control.override_for(Utc::now(), &self.vehicle);
+        if let Some(ManualOverride {
+            mode: ManualMode::Pause,
+            ..
+        }) = manual
+        {
+            tracing::info!(?manual, "control is paused");
+            let moer = signal.current.as_ref().map(|current| current.rate);
+            control.update(self.status(Utc::now(), moer, manual));
+            return Ok(None);
+        }
+
+        if manual.is_none() && !self.charging.allowed_at(Utc::now()) {
             // We need to tell the car to stop charging if we're no longer allowed to charge.
-            if is_charging {
-                let rsp = vehicle.charge_stop().await;
+            let mut commands = Vec::new();
+            if self.model.power_kw() > 0. {
+                let rsp = self.vehicle.charge_stop().await;
                 tracing::info!(?rsp, "charge stop");
-                is_charging = false;
+                commands.push(AuditCommand::new("charge_stop", &rsp));
+                let power_kw = power_after_commands(
+                    &self.charging,
+                    &self.vehicle,
+                    &mut self.model,
+                    rsp.is_ok(),
+                    0.,
+                )
+                .await;
+                self.model.command(Utc::now(), power_kw);
+                self.stabilizer.record(Utc::now(), power_kw);
             }
             // Log the current MOER anyways, for metrics dashboards.
-            metrics::gauge!("emissions_current", current.rate);
-            metrics::gauge!("charge_state", 0.0);
+            if let Some(current) = &signal.current {
+                metrics::gauge!("emissions_current", current.rate, "vehicle" => label.clone());
+                let now = Utc::now();
+                self.model.advance(now);
+                update_baseline(
+                    &mut self.baseline,
+                    &self.charging,
+                    &self.model,
+                    now,
+                    current.rate,
+                    &label,
+                );
+                audit.record(&AuditEntry {
+                    time: now,
+                    unit: label.clone(),
+                    region: self.charging.region,
+                    moer: current.rate,
+                    moer_start: current.start,
+                    forecast_generated_at: None,
+                    emissions_limit: -1,
+                    power_kw: self.model.power_kw(),
+                    interval_minutes: 5,
+                    soc: self.model.soc(),
+                    explanation: "not allowed to charge".to_string(),
+                    commands,
+                });
+            }
+            let moer = signal.current.as_ref().map(|current| current.rate);
+            control.update(self.status(Utc::now(), moer, None));
+            metrics::gauge!("charge_state", 0.0, "vehicle" => label);
             tracing::info!("Not allowed to charge, sleeping");
+            return Ok(None);
         }
 
-        let next = next_window();
-        tokio::time::sleep((next - Utc::now()).to_std().unwrap()).await;
+        signal.prepare(Utc::now(), source).await?;
+        let (history, current, forecast) = signal.data();
+
+        let Self {
+            charging,
+            policy,
+            vehicle,
+            model,
+            stabilizer,
+            ..
+        } = self;
+
+        let now = Utc::now();
+        model.advance(now);
+
+        let mut charge_state = None;
+        if model.needs_observation(charging.max_soc_uncertainty) {
+            charge_state = Some(observe(vehicle, model, true).await?);
+        } else if !model.plugged_in() && vehicle.data().await?.is_online() {
+            // Plugging in wakes the vehicle, so check whether it was plugged in.
+            charge_state = Some(observe(vehicle, model, false).await?);
+        }
+
+        let decision = match &manual {
+            Some(manual) => charge_now(charging, manual),
+            None => {
+                let decision =
+                    policy.decide(charging, now, model.soc(), history, current, forecast);
+                stabilizer.stabilize(charging, now, model.soc(), current, decision)
+            }
+        };
+
+        Ok(Some(Step {
+            now,
+            decision,
+            charge_state,
+            manual,
+            surplus: None,
+        }))
+    }
+
+    /// Acts on a decision, charging at no more than `max_power_kw`.
+    ///
+    /// The vehicle is only woken when the decision would change what the
+    /// vehicle is doing.
+    async fn act(
+        &mut self,
+        step: Step,
+        max_power_kw: f64,
+        signal: &RegionSignal,
+        control: &ControlState,
+        audit: &AuditLog,
+    ) -> Result<(), Error> {
+        let (history, current, forecast) = signal.data();
+        let Self {
+            charging,
+            policy,
+            vehicle,
+            model,
+            stabilizer,
+            baseline,
+        } = self;
+        let label = vehicle.name().to_string();
+
+        let mut step = step;
+        let throttle = |decision: &mut Decision| {
+            if decision.power_kw > max_power_kw {
+                // The budget only grants less than the charger's minimum
+                // power to vehicles asking for less.
+                decision.power_kw = if max_power_kw < charging.min_charge_rate_kw {
+                    0.
+                } else {
+                    max_power_kw
+                };
+                decision.explanation += &format!(", throttled to {:.2} kW", max_power_kw);
+            }
+        };
+        throttle(&mut step.decision);
+
+        let changed = charging_amps(charging, step.decision.power_kw)
+            != charging_amps(charging, model.power_kw());
+        if step.charge_state.is_none() && model.plugged_in() && changed {
+            // Check the actual state of charge before acting on the new decision.
+            step.charge_state = Some(observe(vehicle, model, true).await?);
+            step.decision = redecide(
+                charging,
+                policy.as_ref(),
+                stabilizer,
+                model.soc(),
+                signal,
+                &step,
+            );
+            throttle(&mut step.decision);
+        }
+        let Step {
+            now,
+            mut decision,
+            charge_state,
+            manual,
+            ..
+        } = step;
+
+        tracing::info!(
+            soc = model.soc(),
+            soc_uncertainty = model.uncertainty(),
+            explanation = %decision.explanation,
+            "charge decision"
+        );
+        for (name, value) in &decision.factors {
+            metrics::gauge!(*name, *value, "vehicle" => label.clone());
+        }
+        metrics::gauge!("vehicle_soc_uncertainty", model.uncertainty(), "vehicle" => label.clone());
+        metrics::gauge!("charge_urgency", decision.urgency, "vehicle" => label.clone());
+        if charging.tariff.is_some() {
+            metrics::gauge!("tariff_price", charging.price_at(now), "vehicle" => label.clone());
+        }
+
+        let mut commands = Vec::new();
+        match charge_state {
+            Some(charge_state) if model.plugged_in() => {
+                let amps = if decision.charge() {
+                    command_amps(charging, decision.power_kw, max_power_kw, &charge_state)
+                } else {
+                    0
+                };
+                if decision.charge() && amps == 0 {
+                    decision.power_kw = 0.;
+                    decision.explanation += ", too little power to charge";
+                }
+                let succeeded = if amps > 0 {
+                    let mut set_amps = true;
+                    if amps != charge_state.charge_current_request {
+                        let rsp = vehicle.set_charging_amps(amps).await;
+                        tracing::info!(?rsp, ?amps, "set charging amps");
+                        commands.push(AuditCommand::new(
+                            format!("set_charging_amps {}", amps),
+                            &rsp,
+                        ));
+                        set_amps = rsp.is_ok();
+                    }
+                    let rsp = vehicle.charge_start().await;
+                    tracing::info!(?rsp, "charge start");
+                    commands.push(AuditCommand::new("charge_start", &rsp));
+                    metrics::gauge!("charge_state", 1.0, "vehicle" => label.clone());
+                    metrics::gauge!("charge_amps", amps as f64, "vehicle" => label.clone());
+                    set_amps && rsp.is_ok()
+                } else {
+                    let rsp = vehicle.charge_stop().await;
+                    tracing::info!(?rsp, "charge stop");
+                    commands.push(AuditCommand::new("charge_stop", &rsp));
+                    metrics::gauge!("charge_state", 0.0, "vehicle" => label.clone());
+                    rsp.is_ok()
+                };
+                let power_kw =
+                    power_after_commands(charging, vehicle, model, succeeded, decision.power_kw)
+                        .await;
+                model.command(now, power_kw);
+                stabilizer.record(now, power_kw);
+                metrics::gauge!("charge_switches", stabilizer.switches() as f64, "vehicle" => label.clone());
+            }
+            Some(_) => tracing::info!("vehicle is unplugged"),
+            None => tracing::info!("decision unchanged, not waking vehicle"),
+        }
+
+        update_baseline(baseline, charging, model, now, current.rate, &label);
+        audit.record(&AuditEntry {
+            time: now,
+            unit: label,
+            region: charging.region,
+            moer: current.rate,
+            moer_start: current.start,
+            forecast_generated_at: Some(forecast.generated_at),
+            emissions_limit: decision.emissions_limit,
+            power_kw: model.power_kw(),
+            interval_minutes: 5,
+            soc: model.soc(),
+            explanation: decision.explanation.clone(),
+            commands,
+        });
+
+        let mut status = self.status(now, Some(current.rate), manual);
+        status.decision = Some(DecisionStatus {
+            power_kw: decision.power_kw,
+            emissions_limit: decision.emissions_limit,
+            urgency: decision.urgency,
+            explanation: decision.explanation,
+        });
+        status.plan = Plan::compute(&self.charging, now, status.soc, history, current, forecast)
+            .slots
+            .into_iter()
+            .filter(|slot| slot.power_kw > 0.)
+            .collect();
+        control.update(status);
+
+        Ok(())
+    }
+
+    /// The vehicle's status, without a decision or plan.
+    fn status(
+        &self,
+        now: DateTime<Utc>,
+        moer: Option<f64>,
+        manual: Option<ManualOverride>,
+    ) -> VehicleStatus {
+        let soc = self.model.soc();
+        VehicleStatus {
+            vehicle: self.vehicle.name().to_string(),
+            updated_at: now,
+            soc,
+            soc_uncertainty: self.model.uncertainty(),
+            plugged_in: self.model.plugged_in(),
+            power_kw: self.model.power_kw(),
+            moer,
+            decision: None,
+            goal: self
+                .charging
+                .most_urgent_goal(now, soc)
+                .map(|(goal, required_proportion)| GoalStatus {
+                    time: goal.time,
+                    charge: goal.charge,
+                    required_proportion,
+                }),
+            plan: Vec::new(),
+            manual,
+            emissions_avoided_kg: self.baseline.avoided_kg(),
+        }
+    }
+
+    /// The pending one-off goals for this vehicle, both from the goal store
+    /// and from the calendar.
+    fn one_off_goals(&self, goals: &GoalStore) -> Result<Vec<OneOffGoal>, Error> {
+        let now = Utc::now();
+        let mut one_off_goals = goals.active(now)?;
+        one_off_goals.retain(|goal| match &goal.vehicle {
+            Some(name) => self.vehicle.is_named(name),
+            None => true,
+        });
+        one_off_goals.extend(self.charging.calendar_goals(now)?);
+        one_off_goals.sort_by_key(|goal| goal.time);
+        Ok(one_off_goals)
     }
 }
 
-/// Performs one iteration of charge control, returning `true` if charging is
-/// allowed and `false` otherwise.
-///
-/// This code is split out of the main loop so errors returned by the Tesla API
-/// can be reported per iteration.
-async fn charge_step(
+/// A decision to charge at full power, as requested by a manual override.
+/// Decides again for `step` once the vehicle's state of charge is known,
+/// keeping any surplus solar granted to it while it can still charge.
+fn redecide(
     charging: &config::Charging,
-    current: &Moer,
-    sgip: &mut SgipSignal,
-    vehicle: &Vehicle,
-) -> Result<bool, Error> {
-    // This can be slow, so start in now in another task
-    // and come back to it.
-    let vehicle2 = vehicle.clone();
-    let charge_state = tokio::spawn(async move {
-        tracing::info!("waking vehicle");
-        vehicle2.wake().await?;
-        vehicle2.charge_state().await
-    });
-
-    tracing::info!("Fetching SGIP data");
-    let forecast = sgip.forecast(charging.region).await?;
-    // TODO: don't download this every time
-    let history = History::new(
-        charging.region,
-        sgip.historic_moers(
-            charging.region,
-            // Fetch far enough back to see time-shifted charging windows.
-            Utc::now() - (Duration::days(2) + Duration::hours(charging.flex_charge_hours)),
-            None,
-        )
-        .await?,
+    policy: &dyn ChargePolicy,
+    stabilizer: &Stabilizer,
+    soc: f64,
+    signal: &RegionSignal,
+    step: &Step,
+) -> Decision {
+    let (history, current, forecast) = signal.data();
+    let mut decision = match &step.manual {
+        Some(manual) => charge_now(charging, manual),
+        None => {
+            let decision = policy.decide(charging, step.now, soc, history, current, forecast);
+            stabilizer.stabilize(charging, step.now, soc, current, decision)
+        }
+    };
+    if let Some(surplus) = &step.surplus {
+        if soc < charging.charge_limit_at(step.now) {
+            surplus.apply(&mut decision);
+        }
+    }
+    decision
+}
+
+fn charge_now(charging: &config::Charging, manual: &ManualOverride) -> Decision {
+    Decision {
+        power_kw: charging.charge_rate_kw,
+        emissions_limit: -1,
+        urgency: 1.,
+        explanation: match manual.until {
+            Some(until) => format!("charging now by request until {}", until),
+            None => "charging now by request".to_string(),
+        },
+        factors: Vec::new(),
+    }
+}
+
+/// Account for the interval since the last update against the vehicle's
+/// uncontrolled baseline, and export the totals.
+fn update_baseline(
+    baseline: &mut Baseline,
+    charging: &config::Charging,
+    model: &VehicleModel,
+    now: DateTime<Utc>,
+    rate: f64,
+    label: &str,
+) {
+    let charged_kwh = baseline.charged_kwh();
+    let emissions_kg = baseline.emissions_kg();
+    let baseline_kg = baseline.baseline_kg();
+    baseline.update(
+        charging,
+        now,
+        model.plugged_in(),
+        model.soc(),
+        rate,
+        Charged {
+            estimated_kwh: model.estimated_kwh(),
+            reported: model.observed_energy(),
+        },
     );
 
-    // Ensure the car is online
-    let charge_state = charge_state.await??;
+    // Counters only count up by whole units, so carry fractions over.
+    let increase =
+        |before: f64, after: f64| ((after * 1000.).floor() - (before * 1000.).floor()) as u64;
+    metrics::counter!("charge_energy_wh", increase(charged_kwh, baseline.charged_kwh()), "vehicle" => label.to_string());
+    metrics::counter!("charge_emissions_grams", increase(emissions_kg, baseline.emissions_kg()), "vehicle" => label.to_string());
+    metrics::counter!("baseline_emissions_grams", increase(baseline_kg, baseline.baseline_kg()), "vehicle" => label.to_string());
+    metrics::gauge!("emissions_avoided_kg", baseline.avoided_kg(), "vehicle" => label.to_string());
+}
+
+/// Fetch the vehicle's charge state, optionally waking it first, and update
+/// the model with it.
+async fn observe(
+    vehicle: &Vehicle,
+    model: &mut VehicleModel,
+    wake: bool,
+) -> Result<ChargeState, Error> {
+    if wake {
+        tracing::info!("waking vehicle");
+        vehicle.wake().await?;
+    }
+    let charge_state = vehicle.charge_state().await?;
     tracing::debug!(?charge_state);
+    model.observe(Utc::now(), &charge_state);
+    Ok(charge_state)
+}
 
-    let soc = charge_state.battery_level as f64 / 100.;
-    tracing::info!(?soc);
-
-    if charging
-        .can_charge(Utc::now(), soc, &history, &current, &forecast)
-        .0
-    {
-        let rsp = vehicle.charge_start().await;
-        tracing::info!(?rsp, "charge start");
-        metrics::gauge!("charge_state", 1.0);
-        Ok(true)
+/// The power the vehicle charges at after being commanded to charge at
+/// `commanded_kw`, in kW.
+///
+/// If any command failed, the vehicle is checked for what it is actually
+/// doing, and if that fails too, it is assumed to carry on as before.
+async fn power_after_commands(
+    charging: &config::Charging,
+    vehicle: &Vehicle,
+    model: &mut VehicleModel,
+    succeeded: bool,
+    commanded_kw: f64,
+) -> f64 {
+    if succeeded {
+        return commanded_kw;
+    }
+    match observe(vehicle, model, false).await {
+        Ok(charge_state) => observed_power_kw(charging, &charge_state),
+        Err(e) => {
+            tracing::error!(%e, "failed to check charging after a failed command");
+            model.power_kw()
+        }
+    }
+}
+
+/// The power the vehicle reports charging at, in kW.
+fn observed_power_kw(charging: &config::Charging, charge_state: &ChargeState) -> f64 {
+    if charge_state.charging_state == "Charging" {
+        charge_state.charge_current_request as f64 * charging.charge_voltage / 1000.
     } else {
-        let rsp = vehicle.charge_stop().await;
-        tracing::info!(?rsp, "charge stop");
-        metrics::gauge!("charge_state", 0.0);
-        Ok(false)
+        0.
+    }
+}
+
+/// The charging current to command for a decision to charge at `power_kw`,
+/// in amps, within the budget and the vehicle's limit.  Zero means the power
+/// is too little to charge at.
+fn command_amps(
+    charging: &config::Charging,
+    power_kw: f64,
+    max_power_kw: f64,
+    charge_state: &ChargeState,
+) -> u32 {
+    let mut amps = charging_amps(charging, power_kw);
+    if amps as f64 * charging.charge_voltage > max_power_kw * 1000. {
+        // Round down rather than exceed the budget.
+        amps = amps.saturating_sub(1);
+    }
+    amps.min(charge_state.charge_current_request_max)
+}
+
+/// Convert a charging power into a charging current, in amps.
+fn charging_amps(charging: &config::Charging, power_kw: f64) -> u32 {
+    (power_kw * 1000. / charging.charge_voltage).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use sgip_signal::{Forecast, Moer};
+
+    fn step(now: DateTime<Utc>, power_kw: f64, urgency: f64) -> Step {
+        Step {
+            now,
+            decision: Decision {
+                power_kw,
+                emissions_limit: 0,
+                urgency,
+                explanation: String::new(),
+                factors: Vec::new(),
+            },
+            charge_state: None,
+            manual: None,
+            surplus: None,
+        }
+    }
+
+    #[test]
+    fn unplugged_vehicles_leave_the_site_budget() {
+        let now = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let charging = config::Charging::default();
+        let mut plugged_in = VehicleModel::new(charging.capacity_kwh);
+        plugged_in.observe(now, &ChargeState::example(40, 0., "Stopped"));
+        let mut unplugged = VehicleModel::new(charging.capacity_kwh);
+        unplugged.observe(now, &ChargeState::example(20, 0., "Disconnected"));
+
+        // The unplugged vehicle's goal is more urgent, but the plugged in
+        // vehicle gets the whole budget.
+        let unplugged_step = step(now, charging.charge_rate_kw, 0.9);
+        let plugged_in_step = step(now, charging.charge_rate_kw, 0.2);
+        let requests = [
+            site_request(&charging, &unplugged, Some(&unplugged_step)),
+            site_request(&charging, &plugged_in, Some(&plugged_in_step)),
+        ];
+        assert_eq!(
+            budget::allocate(charging.charge_rate_kw, &requests),
+            vec![0., charging.charge_rate_kw]
+        );
+    }
+
+    #[test]
+    fn observed_power_after_failed_commands() {
+        let charging = config::Charging::default();
+        // The example vehicle requests 32 A.
+        let charging_state = ChargeState::example(40, 0., "Charging");
+        assert!((observed_power_kw(&charging, &charging_state) - 7.68).abs() < 1e-9);
+        let stopped = ChargeState::example(40, 0., "Stopped");
+        assert_eq!(observed_power_kw(&charging, &stopped), 0.);
+    }
+
+    #[test]
+    fn too_little_power_stops_charging() {
+        let charging = config::Charging {
+            min_charge_rate_kw: 0.,
+            ..config::Charging::default()
+        };
+        let charge_state = ChargeState::example(40, 0., "Stopped");
+        assert_eq!(
+            command_amps(&charging, 7.2, f64::INFINITY, &charge_state),
+            30
+        );
+        // Capped by what the vehicle accepts.
+        assert_eq!(
+            command_amps(&charging, 9.6, f64::INFINITY, &charge_state),
+            32
+        );
+        // Less than half an amp rounds to nothing.
+        assert_eq!(
+            command_amps(&charging, 0.1, f64::INFINITY, &charge_state),
+            0
+        );
+        // A budget of less than an amp rounds down to nothing.
+        assert_eq!(command_amps(&charging, 0.3, 0.2, &charge_state), 0);
+    }
+
+    /// A source that records the ranges of MOERs it is asked to backfill.
+    struct CountingSource {
+        inner: crate::FileSource,
+        backfills: Vec<(DateTime<Utc>, Option<DateTime<Utc>>)>,
+    }
+
+    #[async_trait::async_trait]
+    impl SignalSource for CountingSource {
+        async fn moer(&mut self, region: GridRegion) -> Result<Moer, Error> {
+            self.inner.moer(region).await
+        }
+
+        async fn forecast(&mut self, region: GridRegion) -> Result<Forecast, Error> {
+            self.inner.forecast(region).await
+        }
+
+        async fn historic_moers(
+            &mut self,
+            region: GridRegion,
+            start: DateTime<Utc>,
+            end: Option<DateTime<Utc>>,
+        ) -> Result<Vec<Moer>, Error> {
+            self.backfills.push((start, end));
+            self.inner.historic_moers(region, start, end).await
+        }
+
+        async fn historic_forecasts(
+            &mut self,
+            region: GridRegion,
+            start: DateTime<Utc>,
+            end: DateTime<Utc>,
+        ) -> Result<Vec<Forecast>, Error> {
+            self.inner.historic_forecasts(region, start, end).await
+        }
+    }
+
+    #[tokio::test]
+    async fn history_is_backfilled_once() {
+        let region = GridRegion::CAISO_PGE;
+        let t0 = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let moer = |i: i64| Moer {
+            region,
+            rate: 0.5,
+            start: t0 + Duration::minutes(5 * i),
+            duration: Duration::minutes(5),
+        };
+        let forecast = Forecast {
+            region,
+            generated_at: t0,
+            data: std::iter::once((t0, 0.5)).collect(),
+        };
+        let mut source = CountingSource {
+            inner: crate::FileSource::new((-24..48).map(moer).collect(), vec![forecast]),
+            backfills: Vec::new(),
+        };
+
+        // Run well past the lookback, at times between MOER boundaries.
+        let mut signal = RegionSignal::new(region, Duration::hours(1));
+        for i in 0..36 {
+            let current = moer(i);
+            signal.history.insert(current.clone());
+            signal.current = Some(current);
+            signal.forecast = None;
+            let now = t0 + Duration::minutes(5 * i) + Duration::seconds(150);
+            signal.prepare(now, &mut source).await.unwrap();
+        }
+
+        assert_eq!(source.backfills.len(), 1);
+    }
+
+    /// A policy that never charges.
+    #[derive(Debug)]
+    struct Idle;
+
+    impl ChargePolicy for Idle {
+        fn decide(
+            &self,
+            _config: &config::Charging,
+            _now: DateTime<Utc>,
+            _soc: f64,
+            _history: &History,
+            _current: &Moer,
+            _forecast: &Forecast,
+        ) -> Decision {
+            Decision::idle("not charging")
+        }
+    }
+
+    #[test]
+    fn surplus_survives_redeciding() {
+        let region = GridRegion::CAISO_PGE;
+        let now = Utc.ymd(2021, 3, 1).and_hms(20, 0, 0);
+        let charging = config::Charging::default();
+        let mut signal = RegionSignal::new(region, Duration::hours(1));
+        signal.current = Some(Moer {
+            region,
+            rate: 0.5,
+            start: now,
+            duration: Duration::minutes(5),
+        });
+        signal.forecast = Some(Forecast {
+            region,
+            generated_at: now,
+            data: std::iter::once((now, 0.5)).collect(),
+        });
+
+        // The policy decided not to charge, but the vehicle was granted
+        // surplus solar before it was woken.
+        let surplus = Surplus {
+            power_kw: 3.,
+            surplus_kw: 3.5,
+        };
+        let mut step = step(now, 0., 0.);
+        surplus.apply(&mut step.decision);
+        step.surplus = Some(surplus);
+        let stabilizer = Stabilizer::default();
+
+        let decision = redecide(&charging, &Idle, &stabilizer, 0.4, &signal, &step);
+        assert_eq!(decision.power_kw, 3.);
+        assert_eq!(decision.emissions_limit, -1);
+
+        // A vehicle found to be full no longer uses the surplus.
+        let decision = redecide(&charging, &Idle, &stabilizer, 0.95, &signal, &step);
+        assert_eq!(decision.power_kw, 0.);
     }
 }
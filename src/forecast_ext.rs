@@ -5,16 +5,26 @@ use hdrhistogram::Histogram;
 use sgip_signal::Forecast;
 
 pub trait ForecastExt {
-    fn histogram_over(&self, intervals: Vec<Range<DateTime<Utc>>>) -> Histogram<u64>;
+    /// A histogram of `objective(start, rate)` over the forecast intervals
+    /// starting in `intervals`, in thousandths.
+    fn histogram_over(
+        &self,
+        intervals: Vec<Range<DateTime<Utc>>>,
+        objective: impl Fn(DateTime<Utc>, f64) -> f64,
+    ) -> Histogram<u64>;
 }
 
 impl ForecastExt for Forecast {
-    fn histogram_over(&self, intervals: Vec<Range<DateTime<Utc>>>) -> Histogram<u64> {
+    fn histogram_over(
+        &self,
+        intervals: Vec<Range<DateTime<Utc>>>,
+        objective: impl Fn(DateTime<Utc>, f64) -> f64,
+    ) -> Histogram<u64> {
         let mut emissions = Histogram::<u64>::new(3).unwrap();
 
         for m in self.moers() {
             if intervals.iter().any(|range| range.contains(&m.start)) {
-                let rate = (m.rate * 1000.) as u64;
+                let rate = (objective(m.start, m.rate) * 1000.) as u64;
                 emissions.record(rate).unwrap();
             }
         }
@@ -0,0 +1,169 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, Validate};
+
+/// A goal to reach a state of charge by a particular time, once.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OneOffGoal {
+    pub time: DateTime<Utc>,
+    pub charge: f64,
+    /// The VIN or display name of the vehicle the goal is for, or `None` for
+    /// every vehicle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<String>,
+}
+
+impl Validate for OneOffGoal {
+    fn validate(self) -> Result<Self, Error> {
+        config::validate_goal_charge(self.charge)?;
+        Ok(self)
+    }
+}
+
+/// The pending one-off goals, shared between the controller and its API.
+///
+/// Goals are kept in time order, and are dropped once their time has passed.
+/// If the store has a path, every change is written to it as a JSON array, so
+/// that goals survive controller restarts.
+#[derive(Clone, Debug, Default)]
+pub struct GoalStore {
+    path: Option<PathBuf>,
+    goals: Arc<Mutex<Vec<OneOffGoal>>>,
+}
+
+impl GoalStore {
+    /// A store that is not persisted.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Open the store at `path`, which is created on the first change if it
+    /// does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut goals = match fs::read(&path) {
+            Ok(buf) => serde_json::from_slice::<Vec<OneOffGoal>>(&buf)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        goals.sort_by_key(|goal| goal.time);
+        Ok(Self {
+            path: Some(path),
+            goals: Arc::new(Mutex::new(goals)),
+        })
+    }
+
+    /// Add a goal, replacing any existing goal at the same time for the same
+    /// vehicle.
+    pub fn add(&self, goal: OneOffGoal) -> Result<(), Error> {
+        let mut goals = self.goals.lock().unwrap();
+        goals.retain(|existing| (existing.time, &existing.vehicle) != (goal.time, &goal.vehicle));
+        let i = goals.partition_point(|existing| existing.time < goal.time);
+        goals.insert(i, goal);
+        self.save(&goals)
+    }
+
+    /// Remove the goal at `time` for `vehicle`, or for every vehicle if
+    /// `None`, returning whether there was one.
+    pub fn remove(&self, time: DateTime<Utc>, vehicle: Option<&str>) -> Result<bool, Error> {
+        let mut goals = self.goals.lock().unwrap();
+        let len = goals.len();
+        goals.retain(|goal| (goal.time, goal.vehicle.as_deref()) != (time, vehicle));
+        if goals.len() == len {
+            return Ok(false);
+        }
+        self.save(&goals)?;
+        Ok(true)
+    }
+
+    /// The goals that are still in the future, after dropping expired ones.
+    pub fn active(&self, now: DateTime<Utc>) -> Result<Vec<OneOffGoal>, Error> {
+        let mut goals = self.goals.lock().unwrap();
+        let expired = goals.partition_point(|goal| goal.time <= now);
+        if expired > 0 {
+            tracing::info!(expired = ?&goals[..expired], "dropping expired one-off goals");
+            goals.drain(..expired);
+            self.save(&goals)?;
+        }
+        Ok(goals.clone())
+    }
+
+    fn save(&self, goals: &[OneOffGoal]) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first so a crash can't truncate the store.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(goals)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn goals_persist_and_expire() {
+        let dir = std::env::temp_dir().join(format!("sgip-goals-test-{}", std::process::id()));
+        let path = dir.join("goals.json");
+        let t0 = Utc.ymd(2021, 3, 6).and_hms(15, 0, 0);
+        let goal = |hours, charge| OneOffGoal {
+            time: t0 + Duration::hours(hours),
+            charge,
+            vehicle: None,
+        };
+
+        let store = GoalStore::open(&path).unwrap();
+        store.add(goal(24, 0.9)).unwrap();
+        store.add(goal(0, 0.5)).unwrap();
+        store.add(goal(24, 0.95)).unwrap();
+
+        // Goals are reloaded in time order, with the replacement applied.
+        let reopened = GoalStore::open(&path).unwrap();
+        assert_eq!(
+            reopened.active(t0 - Duration::hours(1)).unwrap(),
+            vec![goal(0, 0.5), goal(24, 0.95)]
+        );
+
+        // Passed goals are dropped, including from disk.
+        assert_eq!(reopened.active(t0).unwrap(), vec![goal(24, 0.95)]);
+        let reopened = GoalStore::open(&path).unwrap();
+        assert_eq!(
+            reopened.active(t0 - Duration::hours(1)).unwrap(),
+            vec![goal(24, 0.95)]
+        );
+
+        // Only the goal for the given vehicle is removed.
+        let red = OneOffGoal {
+            vehicle: Some("Red".to_string()),
+            ..goal(24, 0.8)
+        };
+        reopened.add(red.clone()).unwrap();
+        assert!(reopened.remove(red.time, None).unwrap());
+        assert!(!reopened.remove(red.time, None).unwrap());
+        assert_eq!(reopened.active(t0).unwrap(), vec![red.clone()]);
+        assert!(reopened.remove(red.time, Some("Red")).unwrap());
+        assert!(GoalStore::open(&path)
+            .unwrap()
+            .active(t0)
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,20 +1,88 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use hdrhistogram::Histogram;
 use sgip_signal::{GridRegion, Moer};
 use std::{collections::BTreeMap, ops::Range};
 
+use crate::intervals::{self, RangeExt};
+
+/// The interval between MOER data points.
+pub(crate) const MOER_INTERVAL_MINUTES: i64 = 5;
+
 pub struct History {
     region: GridRegion,
     data: BTreeMap<DateTime<Utc>, f64>,
+    /// Ranges that have already been backfilled, so that gaps in the upstream
+    /// data are not requested again.
+    backfilled: Vec<Range<DateTime<Utc>>>,
 }
 
 impl History {
     pub fn new(region: GridRegion, moers: Vec<Moer>) -> Self {
-        let mut data = BTreeMap::default();
+        let mut history = Self {
+            region,
+            data: BTreeMap::default(),
+            backfilled: Vec::new(),
+        };
+        history.extend(moers);
+        history
+    }
+
+    pub fn region(&self) -> GridRegion {
+        self.region
+    }
+
+    /// Add a newly observed MOER to the history.
+    pub fn insert(&mut self, moer: Moer) {
+        self.data.insert(moer.start, moer.rate);
+    }
+
+    pub fn extend(&mut self, moers: impl IntoIterator<Item = Moer>) {
         for moer in moers {
-            data.insert(moer.start, moer.rate);
+            self.insert(moer);
         }
-        Self { region, data }
+    }
+
+    /// Add MOERs fetched to fill `range`, recording that the range has been
+    /// backfilled even if the data has gaps.
+    pub fn backfill(&mut self, range: Range<DateTime<Utc>>, moers: Vec<Moer>) {
+        self.extend(moers);
+        self.backfilled.push(range);
+    }
+
+    /// Returns the parts of `range` that have neither data nor have already
+    /// been backfilled.
+    pub fn gaps(&self, range: Range<DateTime<Utc>>) -> Vec<Range<DateTime<Utc>>> {
+        let interval = Duration::minutes(MOER_INTERVAL_MINUTES);
+
+        // The last MOER starting before the range may cover its start.
+        let start = self
+            .data
+            .range(..=range.start)
+            .next_back()
+            .map(|(start, _)| *start)
+            .filter(|start| *start + interval > range.start)
+            .unwrap_or(range.start);
+        let starts = self.data.range(start..range.end).map(|(start, _)| *start);
+        intervals::gaps(starts, start..range.end, interval)
+            .into_iter()
+            .flat_map(|gap| {
+                self.backfilled.iter().fold(vec![gap], |gaps, filled| {
+                    gaps.iter().flat_map(|gap| gap.difference(filled)).collect()
+                })
+            })
+            .collect()
+    }
+
+    /// Discard data from before `time`, keeping the MOER in effect at `time`.
+    pub fn prune(&mut self, time: DateTime<Utc>) {
+        let keep_from = self
+            .data
+            .range(..=time)
+            .next_back()
+            .map(|(start, _)| *start)
+            .unwrap_or(time);
+        self.data = self.data.split_off(&keep_from);
+        self.backfilled.retain(|range| range.end > time);
     }
 
     pub fn at(&self, time: DateTime<Utc>) -> Option<Moer> {
@@ -33,12 +101,18 @@ impl History {
         }
     }
 
-    pub fn histogram_over(&self, intervals: Vec<Range<DateTime<Utc>>>) -> Histogram<u64> {
+    /// A histogram of `objective(start, rate)` over the MOERs starting in
+    /// `intervals`, in thousandths.
+    pub fn histogram_over(
+        &self,
+        intervals: Vec<Range<DateTime<Utc>>>,
+        objective: impl Fn(DateTime<Utc>, f64) -> f64,
+    ) -> Histogram<u64> {
         let mut emissions = Histogram::<u64>::new(3).unwrap();
 
         for (start, rate) in self.data.iter() {
             if intervals.iter().any(|range| range.contains(start)) {
-                let rate = (rate * 1000.) as u64;
+                let rate = (objective(*start, *rate) * 1000.) as u64;
                 emissions.record(rate).unwrap();
             }
         }
@@ -46,3 +120,45 @@ impl History {
         emissions
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn moer(start: DateTime<Utc>) -> Moer {
+        Moer {
+            region: GridRegion::CAISO_PGE,
+            rate: 0.5,
+            start,
+            duration: Duration::minutes(5),
+        }
+    }
+
+    #[test]
+    fn gaps_and_backfill() {
+        let t0 = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let t = |minutes| t0 + Duration::minutes(minutes);
+
+        let mut history = History::new(GridRegion::CAISO_PGE, vec![moer(t(10)), moer(t(15))]);
+        history.insert(moer(t(30)));
+
+        assert_eq!(
+            history.gaps(t(0)..t(40)),
+            vec![t(0)..t(10), t(20)..t(30), t(35)..t(40)]
+        );
+
+        // Upstream data is missing at 25, but it shouldn't be requested again.
+        history.backfill(t(20)..t(30), vec![moer(t(20))]);
+        assert_eq!(history.gaps(t(0)..t(40)), vec![t(0)..t(10), t(35)..t(40)]);
+
+        history.prune(t(15));
+        assert!(history.at(t(12)).is_none());
+        assert_eq!(history.gaps(t(15)..t(35)), vec![]);
+
+        // Pruning between data points keeps the MOER covering the new start.
+        history.prune(t(17));
+        assert_eq!(history.at(t(17)).unwrap().start, t(15));
+        assert_eq!(history.gaps(t(17)..t(35)), vec![]);
+    }
+}
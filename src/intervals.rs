@@ -1,12 +1,19 @@
 use std::ops::Range;
 
-use chrono::{Date, DateTime, Duration, TimeZone, Utc};
-use chrono_tz::US::Pacific;
+use chrono::{
+    Date, DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
 
 use super::config;
 
 pub trait RangeExt: Sized {
     fn intersect(&self, other: &Self) -> Option<Self>;
+    /// Returns the nonempty parts of `self` that are not in `other`.
+    fn difference(&self, other: &Self) -> Vec<Self>;
+    /// Returns the range covering both `self` and `other`, if they overlap or
+    /// touch.
+    fn union(&self, other: &Self) -> Option<Self>;
 }
 
 impl<R: Ord + Clone> RangeExt for Range<R> {
@@ -20,11 +27,129 @@ impl<R: Ord + Clone> RangeExt for Range<R> {
             Some(max(a.start.clone(), b.start.clone())..min(a.end.clone(), b.end.clone()))
         }
     }
+
+    fn difference(&self, other: &Self) -> Vec<Self> {
+        use std::cmp::{max, min};
+        let before = self.start.clone()..min(self.end.clone(), other.start.clone());
+        let after = max(self.start.clone(), other.end.clone())..self.end.clone();
+        vec![before, after]
+            .into_iter()
+            .filter(|range| range.start < range.end)
+            .collect()
+    }
+
+    fn union(&self, other: &Self) -> Option<Self> {
+        use std::cmp::{max, min};
+        let a = self;
+        let b = other;
+        if b.start > a.end || a.start > b.end {
+            None
+        } else {
+            Some(min(a.start.clone(), b.start.clone())..max(a.end.clone(), b.end.clone()))
+        }
+    }
+}
+
+/// A union of ranges, stored as disjoint nonempty ranges in order.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RangeSet<R>(Vec<Range<R>>);
+
+impl<R> Default for RangeSet<R> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<R: Ord + Clone> RangeSet<R> {
+    /// Add `range` to the set, merging it with any ranges it overlaps or
+    /// touches.
+    pub fn insert(&mut self, range: Range<R>) {
+        if range.start >= range.end {
+            return;
+        }
+        let mut merged = range;
+        let mut ranges = Vec::with_capacity(self.0.len() + 1);
+        for existing in self.0.drain(..) {
+            match merged.union(&existing) {
+                Some(union) => merged = union,
+                None => ranges.push(existing),
+            }
+        }
+        let i = ranges.partition_point(|existing| existing.start < merged.start);
+        ranges.insert(i, merged);
+        self.0 = ranges;
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        let mut ranges = self.0.clone();
+        for cut in &other.0 {
+            ranges = ranges
+                .iter()
+                .flat_map(|range| range.difference(cut))
+                .collect();
+        }
+        Self(ranges)
+    }
+}
+
+impl<R: Ord + Clone> std::iter::FromIterator<Range<R>> for RangeSet<R> {
+    fn from_iter<I: IntoIterator<Item = Range<R>>>(iter: I) -> Self {
+        let mut set = Self::default();
+        for range in iter {
+            set.insert(range);
+        }
+        set
+    }
+}
+
+impl<R> IntoIterator for RangeSet<R> {
+    type Item = Range<R>;
+    type IntoIter = std::vec::IntoIter<Range<R>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
 }
 
 type Interval = Range<DateTime<Utc>>;
 
-struct DateIterator<Tz: TimeZone>(pub Date<Tz>);
+/// Returns the parts of `range` not covered by data points that are each
+/// valid for `interval`, such as 5-minute MOERs.
+pub(crate) fn gaps(
+    points: impl IntoIterator<Item = DateTime<Utc>>,
+    range: Interval,
+    interval: Duration,
+) -> Vec<Interval> {
+    let mut gaps = Vec::new();
+    let mut covered_until = range.start;
+    for point in points.into_iter().filter(|point| range.contains(point)) {
+        if point > covered_until {
+            gaps.push(covered_until..point);
+        }
+        covered_until = std::cmp::max(covered_until, point + interval);
+    }
+    if covered_until < range.end {
+        gaps.push(covered_until..range.end);
+    }
+    gaps
+}
+
+/// Resolve a local date and time in `timezone` to an instant.
+///
+/// Local times skipped when clocks spring forward resolve to the end of the
+/// gap, when the clocks resume, and local times repeated when clocks fall back
+/// resolve to their first occurrence.
+pub(crate) fn local_instant(timezone: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    // Transitions happen on minute boundaries, so the end of a gap is the
+    // first whole minute after it that exists.
+    let minute = local.with_second(0).unwrap().with_nanosecond(0).unwrap();
+    std::iter::once(local)
+        .chain((1..=(24 * 60)).map(|i| minute + Duration::minutes(i)))
+        .find_map(|local| timezone.from_local_datetime(&local).earliest())
+        .expect("time zone transitions skip less than a day")
+        .with_timezone(&Utc)
+}
+
+pub(crate) struct DateIterator<Tz: TimeZone>(pub Date<Tz>);
 
 impl<Tz: TimeZone> Iterator for DateIterator<Tz> {
     type Item = Date<Tz>;
@@ -36,21 +161,65 @@ impl<Tz: TimeZone> Iterator for DateIterator<Tz> {
 }
 
 impl config::Charging {
-    pub fn allowed_times_during(&self, range: Interval) -> impl Iterator<Item = Interval> {
-        let allowed_times = self.allowed_times.clone();
-        // this function is only for californians
-        let start_date = range.start.with_timezone(&Pacific).date();
-
-        let charging_times_for_day = move |date: Date<chrono_tz::Tz>| {
-            allowed_times.clone().into_iter().map(move |(start, end)| {
-                date.and_time(start).unwrap().with_timezone(&Utc)
-                    ..date.and_time(end).unwrap().with_timezone(&Utc)
+    pub fn allowed_times_during(&self, range: Interval) -> impl Iterator<Item = Interval> + '_ {
+        let timezone = self.timezone();
+        // Start from the day before, whose overnight windows may still be open.
+        let start_date = range.start.with_timezone(&timezone).date().pred();
+        let end_date = range.end.with_timezone(&timezone).date();
+        let range_start = range.start;
+        let blackouts = self.blackouts();
+
+        let charging_times_for_day = move |date: Date<Tz>| {
+            let date = date.naive_local();
+            let mut allowed_times = self.schedule_on(date).allowed_times.to_vec();
+            allowed_times.sort();
+            allowed_times.into_iter().map(move |(start, end)| {
+                let end_date = if end < start { date.succ() } else { date };
+                local_instant(timezone, date.and_time(start))
+                    ..local_instant(timezone, end_date.and_time(end))
             })
         };
 
         DateIterator(start_date)
+            // Overrides may leave days without any windows.
+            .take_while(move |date| *date <= end_date)
             .flat_map(charging_times_for_day)
+            // An overnight window may run into the next day's windows when
+            // their schedules differ, so trim windows to start after the
+            // previous one ends.
+            .scan(range_start, |covered_until, interval| {
+                let start = std::cmp::max(interval.start, *covered_until);
+                let end = std::cmp::max(interval.end, start);
+                *covered_until = end;
+                Some(start..end)
+            })
+            // Skip windows that end before the range starts.
+            .skip_while(move |interval| interval.end <= range_start)
             .map_while(move |interval| interval.intersect(&range))
+            .filter(|interval| interval.start < interval.end)
+            .flat_map(move |interval| {
+                std::iter::once(interval)
+                    .collect::<RangeSet<_>>()
+                    .difference(&blackouts)
+            })
+    }
+
+    /// The blackout periods, merged where they overlap.
+    fn blackouts(&self) -> RangeSet<DateTime<Utc>> {
+        let timezone = self.timezone();
+        self.blackouts
+            .iter()
+            .map(|blackout| {
+                let range = blackout.local_range();
+                local_instant(timezone, range.start)..local_instant(timezone, range.end)
+            })
+            .collect()
+    }
+
+    /// The instant at which the local `time` on `date` occurs, resolved as in
+    /// [`local_instant`] across daylight saving time transitions.
+    pub fn local_instant(&self, date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+        local_instant(self.timezone(), date.and_time(time))
     }
 
     pub fn allowed_at(&self, time: DateTime<Utc>) -> bool {
@@ -64,6 +233,7 @@ impl config::Charging {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono_tz::US::Pacific;
 
     #[test]
     fn charging_intervals() {
@@ -76,4 +246,274 @@ mod tests {
 
         println!("{:?}", times);
     }
+
+    #[test]
+    fn charging_intervals_after_last_window() {
+        let charging = config::Charging::default();
+
+        // The default window is 00:00-15:00, so starting at 16:00 the first
+        // allowed interval is the next day's.
+        let start = Pacific
+            .ymd(2021, 3, 1)
+            .and_hms(16, 0, 0)
+            .with_timezone(&Utc);
+        let times = charging
+            .allowed_times_during(start..(start + Duration::days(2)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            times,
+            vec![
+                Pacific.ymd(2021, 3, 2).and_hms(0, 0, 0).with_timezone(&Utc)
+                    ..Pacific
+                        .ymd(2021, 3, 2)
+                        .and_hms(15, 0, 0)
+                        .with_timezone(&Utc),
+                Pacific.ymd(2021, 3, 3).and_hms(0, 0, 0).with_timezone(&Utc)
+                    ..Pacific
+                        .ymd(2021, 3, 3)
+                        .and_hms(15, 0, 0)
+                        .with_timezone(&Utc),
+            ]
+        );
+        assert!(!charging.allowed_at(start));
+    }
+
+    #[test]
+    fn allowed_times_in_configured_timezone() {
+        use chrono_tz::{America::New_York, Tz};
+        use sgip_signal::GridRegion;
+
+        let charging = config::Charging {
+            timezone: Some(Tz::America__New_York),
+            ..config::Charging::default()
+        };
+        let start = New_York
+            .ymd(2021, 3, 1)
+            .and_hms(0, 0, 0)
+            .with_timezone(&Utc);
+        let times = charging
+            .allowed_times_during(start..(start + Duration::days(1)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            vec![
+                start
+                    ..New_York
+                        .ymd(2021, 3, 1)
+                        .and_hms(15, 0, 0)
+                        .with_timezone(&Utc)
+            ]
+        );
+
+        // Without an explicit time zone, it follows the region.
+        let charging = config::Charging {
+            region: GridRegion::WALC,
+            ..config::Charging::default()
+        };
+        assert_eq!(charging.timezone(), Tz::America__Phoenix);
+    }
+
+    #[test]
+    fn local_instants_across_dst() {
+        let charging = config::Charging::default();
+        let time = |h, m| NaiveTime::from_hms(h, m, 0);
+
+        // Clocks spring forward from 02:00 PST to 03:00 PDT.
+        let spring = NaiveDate::from_ymd(2021, 3, 14);
+        assert_eq!(
+            charging.local_instant(spring, time(2, 30)),
+            Utc.ymd(2021, 3, 14).and_hms(10, 0, 0)
+        );
+        assert_eq!(
+            charging.local_instant(spring, time(1, 59)),
+            Utc.ymd(2021, 3, 14).and_hms(9, 59, 0)
+        );
+
+        // Clocks fall back from 02:00 PDT to 01:00 PST.
+        let fall = NaiveDate::from_ymd(2021, 11, 7);
+        assert_eq!(
+            charging.local_instant(fall, time(1, 30)),
+            Utc.ymd(2021, 11, 7).and_hms(8, 30, 0)
+        );
+    }
+
+    #[test]
+    fn allowed_times_across_dst() {
+        let time = |h, m| NaiveTime::from_hms(h, m, 0);
+        let spring = NaiveDate::from_ymd(2021, 3, 14);
+        let fall = NaiveDate::from_ymd(2021, 11, 7);
+
+        // (date, window, allowed hours on that date)
+        let cases = vec![
+            (spring, (time(0, 0), time(15, 0)), 14.),
+            // Starting in the gap.
+            (spring, (time(2, 0), time(4, 0)), 1.),
+            // Ending in the gap.
+            (spring, (time(0, 0), time(2, 30)), 2.),
+            // Entirely in the gap.
+            (spring, (time(2, 0), time(2, 30)), 0.),
+            (spring, (time(1, 0), time(1, 30)), 0.5),
+            // Overnight, from the night before and into the next day.
+            (spring, (time(22, 0), time(7, 0)), 8.),
+            (fall, (time(0, 0), time(15, 0)), 16.),
+            // Entirely in the repeated hour.
+            (fall, (time(1, 0), time(1, 30)), 0.5),
+            // Ending after the repeated hour.
+            (fall, (time(0, 0), time(2, 0)), 3.),
+            // Starting in the repeated hour.
+            (fall, (time(1, 30), time(3, 0)), 2.5),
+            (fall, (time(22, 0), time(7, 0)), 10.),
+        ];
+
+        for (date, window, hours) in cases {
+            let charging = config::Charging {
+                allowed_times: vec![window],
+                ..config::Charging::default()
+            };
+            let start = charging.local_instant(date, time(0, 0));
+            let end = charging.local_instant(date.succ(), time(0, 0));
+            let allowed = charging
+                .allowed_times_during(start..end)
+                .map(|range| (range.end - range.start).num_minutes())
+                .sum::<i64>();
+            assert_eq!(allowed, (hours * 60.) as i64, "{} {:?}", date, window);
+
+            let mut now = start;
+            while now < end {
+                charging.allowed_at(now);
+                now = now + Duration::minutes(5);
+            }
+        }
+    }
+
+    #[test]
+    fn overnight_allowed_times() {
+        let time = |h| NaiveTime::from_hms(h, 0, 0);
+        let charging = config::Charging {
+            allowed_times: vec![(time(12), time(14)), (time(22), time(7))],
+            ..config::Charging::default()
+        };
+        let at = |d, h| Pacific.ymd(2021, 3, d).and_hms(h, 0, 0).with_timezone(&Utc);
+
+        let times = charging
+            .allowed_times_during(at(2, 3)..at(3, 12))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            vec![
+                at(2, 3)..at(2, 7),
+                at(2, 12)..at(2, 14),
+                at(2, 22)..at(3, 7)
+            ]
+        );
+
+        assert!(charging.allowed_at(at(2, 23)));
+        assert!(charging.allowed_at(at(3, 5)));
+        assert!(!charging.allowed_at(at(3, 8)));
+    }
+
+    #[test]
+    fn allowed_times_with_weekday_overrides() {
+        use chrono::Weekday;
+
+        let time = |h| NaiveTime::from_hms(h, 0, 0);
+        let charging = config::Charging {
+            allowed_times: vec![(time(22), time(7))],
+            overrides: vec![
+                config::Override {
+                    weekdays: vec![Weekday::Sat],
+                    allowed_times: Some(vec![(time(6), time(10))]),
+                    ..config::Override::default()
+                },
+                config::Override {
+                    weekdays: vec![Weekday::Sun],
+                    allowed_times: Some(vec![]),
+                    ..config::Override::default()
+                },
+            ],
+            ..config::Charging::default()
+        };
+        // 2021-03-05 is a Friday.
+        let at = |d, h| Pacific.ymd(2021, 3, d).and_hms(h, 0, 0).with_timezone(&Utc);
+
+        let times = charging
+            .allowed_times_during(at(5, 12)..at(8, 12))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            // Saturday's window starts when Friday night's ends, and no
+            // windows start on Saturday night or Sunday.
+            vec![at(5, 22)..at(6, 7), at(6, 7)..at(6, 10)]
+        );
+    }
+
+    #[test]
+    fn range_set_union_and_difference() {
+        let mut set = vec![5..7, 0..2, 1..3, 3..4, 9..9]
+            .into_iter()
+            .collect::<RangeSet<_>>();
+        // Overlapping and touching ranges merge, and empty ones are dropped.
+        assert_eq!(set, RangeSet(vec![0..4, 5..7]));
+
+        set.insert(4..5);
+        set.insert(8..10);
+        assert_eq!(set, RangeSet(vec![0..7, 8..10]));
+
+        let cut = RangeSet(vec![1..2, 6..9]);
+        assert_eq!(
+            set.difference(&cut).into_iter().collect::<Vec<_>>(),
+            vec![0..1, 2..6, 9..10]
+        );
+    }
+
+    #[test]
+    fn allowed_times_exclude_blackouts() {
+        use config::{Blackout, LocalTime};
+
+        let time = |h| NaiveTime::from_hms(h, 0, 0);
+        let date = |d| NaiveDate::from_ymd(2021, 3, d);
+        let charging = config::Charging {
+            allowed_times: vec![(time(22), time(7)), (time(12), time(16))],
+            blackouts: vec![
+                // A peak event in the afternoon.
+                Blackout {
+                    start: LocalTime::DateTime(date(1).and_time(time(13))),
+                    end: Some(LocalTime::DateTime(date(1).and_time(time(15)))),
+                },
+                // Away for two days, overlapping a single-day holiday.
+                Blackout {
+                    start: LocalTime::Date(date(3)),
+                    end: Some(LocalTime::Date(date(4))),
+                },
+                Blackout {
+                    start: LocalTime::Date(date(4)),
+                    end: None,
+                },
+            ],
+            ..config::Charging::default()
+        };
+        let at = |d, h| Pacific.ymd(2021, 3, d).and_hms(h, 0, 0).with_timezone(&Utc);
+
+        let times = charging
+            .allowed_times_during(at(1, 0)..at(6, 0))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            vec![
+                at(1, 0)..at(1, 7),
+                at(1, 12)..at(1, 13),
+                at(1, 15)..at(1, 16),
+                at(1, 22)..at(2, 7),
+                at(2, 12)..at(2, 16),
+                // The overnight window is cut off at midnight.
+                at(2, 22)..at(3, 0),
+                at(5, 0)..at(5, 7),
+                at(5, 12)..at(5, 16),
+                at(5, 22)..at(6, 0),
+            ]
+        );
+        assert!(!charging.allowed_at(at(1, 14)));
+        assert!(!charging.allowed_at(at(4, 14)));
+    }
 }
@@ -1,19 +1,48 @@
 #![feature(iter_map_while)]
 
+pub mod api;
+mod archive;
+mod audit;
+mod baseline;
+mod budget;
+mod calendar;
 mod chrono_ext;
+mod control;
 mod controller;
 mod forecast_ext;
+mod goals;
 mod history;
 mod intervals;
+mod policy;
+mod powerwall;
+mod report;
+mod schedule;
+mod signal;
 mod simulator;
+mod stability;
+mod sunspec;
+mod tariff;
 pub mod tesla;
+mod vehicle_model;
+pub mod watttime;
 
 use chrono_ext::DurationExt;
 use forecast_ext::ForecastExt;
 
 pub mod config;
 
+pub use archive::Archive;
+pub use audit::{AuditCommand, AuditEntry, AuditLog};
 pub use config::{Config, Validate};
-pub use controller::start;
+pub use control::{
+    ControlState, DecisionStatus, GoalStatus, ManualMode, ManualOverride, VehicleStatus,
+};
+pub use controller::{start, start_with_policy, SiteControl};
+pub use goals::{GoalStore, OneOffGoal};
 pub use history::History;
-pub use simulator::Simulator;
+pub use policy::{ChargePolicy, Decision, Plan, PlannerPolicy, QuantilePolicy, Slot};
+pub use powerwall::{Dispatch, PowerwallController, PowerwallDecision};
+pub use report::{report_html, summarize, write_report_csv, ReportPeriod, ReportRow};
+pub use signal::{Archived, FileSource, SignalSource};
+pub use simulator::{DataSource, Simulator};
+pub use vehicle_model::VehicleModel;
@@ -1,11 +1,15 @@
 use std::{fs::File, io::prelude::*, net::SocketAddr, path::PathBuf};
 
-use anyhow::Error;
-use chrono::{Duration, NaiveTime, Utc};
-use chrono_tz::US::Pacific;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
 use structopt::StructOpt;
 
-use sgip_ev_charging::{Config, Simulator, Validate};
+use sgip_ev_charging::{
+    api::{GoalVehicle, NewGoal, NewOverride, Status},
+    config::{Policy, VehicleConfig},
+    AuditLog, Config, ControlState, DataSource, GoalStore, ManualOverride, OneOffGoal,
+    PowerwallController, ReportPeriod, Simulator, SiteControl, Validate,
+};
 
 #[derive(Debug, StructOpt)]
 struct Opt {
@@ -30,6 +34,26 @@ enum Command {
         /// Prometheus endpoint address
         #[structopt(short, long)]
         prometheus_endpoint: Option<SocketAddr>,
+        /// Address to serve the controller's API on (defaults to the
+        /// address in the config, if any)
+        #[structopt(short, long)]
+        api_endpoint: Option<SocketAddr>,
+    },
+    /// Query or steer a running charge controller.
+    Control {
+        /// API endpoint address of the running controller
+        #[structopt(short, long)]
+        api_endpoint: SocketAddr,
+        #[structopt(subcommand)]
+        cmd: ControlCommand,
+    },
+    /// Manage one-off goals on a running charge controller.
+    Goals {
+        /// API endpoint address of the running controller
+        #[structopt(short, long)]
+        api_endpoint: SocketAddr,
+        #[structopt(subcommand)]
+        cmd: GoalCommand,
     },
     /// Simulate the charging algorithm over historical data over a number of backtest days.
     Simulator {
@@ -42,6 +66,26 @@ enum Command {
         /// Prefix for output CSVs
         #[structopt(short, long)]
         prefix: String,
+        /// Policies to compare side by side, each written to its own CSV
+        /// (defaults to the policy in the config)
+        #[structopt(long)]
+        policies: Vec<Policy>,
+        /// First day to simulate, as YYYY-MM-DD (defaults to a few days ago,
+        /// counting backwards for each backtest day)
+        #[structopt(long)]
+        start_date: Option<NaiveDate>,
+        /// Read MOERs and forecasts from this archive directory instead of
+        /// the SGIP API
+        #[structopt(long, parse(from_os_str), conflicts_with_all = &["moers", "forecasts"])]
+        archive: Option<PathBuf>,
+        /// Read MOERs from this CSV (start,rate) or SGIP JSON dump instead of
+        /// the SGIP API
+        #[structopt(long, parse(from_os_str), requires = "forecasts")]
+        moers: Option<PathBuf>,
+        /// Read forecasts from this SGIP JSON or JSON lines dump instead of
+        /// the SGIP API
+        #[structopt(long, parse(from_os_str), requires = "moers")]
+        forecasts: Option<PathBuf>,
     },
     /// Merge the outputs of simulator runs into a single CSV.
     MergeCsv {
@@ -58,6 +102,90 @@ enum Command {
         #[structopt(parse(from_os_str))]
         inputs: Vec<PathBuf>,
     },
+    /// Summarize the energy charged and its emissions from the audit log.
+    Report {
+        /// Config file path
+        #[structopt(short, long, parse(from_os_str))]
+        config: PathBuf,
+        /// Group rows by month or year
+        #[structopt(long, default_value = "year")]
+        period: ReportPeriod,
+        /// First local day to include, as YYYY-MM-DD
+        #[structopt(long)]
+        since: Option<NaiveDate>,
+        /// Local day to stop before, as YYYY-MM-DD
+        #[structopt(long)]
+        until: Option<NaiveDate>,
+        /// Write the report as CSV to this path (defaults to stdout if no
+        /// other output is given)
+        #[structopt(long, parse(from_os_str))]
+        csv: Option<PathBuf>,
+        /// Write the report as HTML to this path
+        #[structopt(long, parse(from_os_str))]
+        html: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ControlCommand {
+    /// Print the controller's status as JSON.
+    Status,
+    /// Stop sending commands to vehicles, leaving them alone.
+    Pause {
+        /// How long to pause for, in hours (defaults to until resumed)
+        #[structopt(long)]
+        hours: Option<f64>,
+        /// VIN or display name of the vehicle to pause (defaults to every
+        /// vehicle)
+        #[structopt(long)]
+        vehicle: Option<String>,
+    },
+    /// Charge at full power, whatever the emissions.
+    ChargeNow {
+        /// How long to charge for, in hours
+        #[structopt(long)]
+        hours: f64,
+        /// VIN or display name of the vehicle to charge (defaults to every
+        /// vehicle)
+        #[structopt(long)]
+        vehicle: Option<String>,
+    },
+    /// Clear a pause or charge-now override.
+    Resume {
+        /// VIN or display name of the vehicle to resume (defaults to the
+        /// override for every vehicle)
+        #[structopt(long)]
+        vehicle: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum GoalCommand {
+    /// List the pending one-off goals.
+    List,
+    /// Add a one-off goal, replacing any other goal at the same time.
+    Add {
+        /// Local time to reach the goal by, as YYYY-MM-DDTHH:MM:SS
+        #[structopt(long)]
+        by: NaiveDateTime,
+        /// State of charge to reach, between 0 and 1
+        #[structopt(long)]
+        charge: f64,
+        /// VIN or display name of the vehicle the goal is for (defaults to
+        /// every vehicle)
+        #[structopt(long)]
+        vehicle: Option<String>,
+    },
+    /// Remove a one-off goal.
+    Remove {
+        /// Time of the goal to remove, as listed
+        #[structopt(long)]
+        time: DateTime<Utc>,
+        /// Vehicle of the goal to remove, as listed (defaults to the goal for
+        /// every vehicle)
+        #[structopt(long)]
+        vehicle: Option<String>,
+    },
 }
 
 fn load_config(config: PathBuf) -> Config {
@@ -78,9 +206,18 @@ async fn main() {
         Command::Start {
             config,
             prometheus_endpoint,
+            api_endpoint,
         } => {
             let config = load_config(config);
-            start(config, prometheus_endpoint).await.unwrap();
+            start(config, prometheus_endpoint, api_endpoint)
+                .await
+                .unwrap();
+        }
+        Command::Control { api_endpoint, cmd } => {
+            control(api_endpoint, cmd).await.unwrap();
+        }
+        Command::Goals { api_endpoint, cmd } => {
+            goals(api_endpoint, cmd).await.unwrap();
         }
         Command::GenerateConfig { output } => {
             let config_toml = toml::to_string_pretty(&Config::default()).unwrap();
@@ -93,9 +230,21 @@ async fn main() {
             config,
             backtest_days,
             prefix,
+            policies,
+            start_date,
+            archive,
+            moers,
+            forecasts,
         } => {
             let config = load_config(config);
-            simulator(config, backtest_days, prefix).await.unwrap();
+            let source = match (archive, moers, forecasts) {
+                (Some(archive), _, _) => DataSource::Archive(archive),
+                (None, Some(moers), Some(forecasts)) => DataSource::Files { moers, forecasts },
+                _ => DataSource::Online,
+            };
+            simulator(config, backtest_days, prefix, policies, start_date, source)
+                .await
+                .unwrap();
         }
         Command::MergeCsv {
             output,
@@ -105,10 +254,27 @@ async fn main() {
         } => {
             merge_csv(output, inputs, soc_only, emissions_only).unwrap();
         }
+        Command::Report {
+            config,
+            period,
+            since,
+            until,
+            csv,
+            html,
+        } => {
+            let config = load_config(config);
+            report(config, period, since, until, csv, html).unwrap();
+        }
     }
 }
 
-async fn start(config: Config, prometheus_endpoint: Option<SocketAddr>) -> Result<(), Error> {
+async fn start(
+    config: Config,
+    prometheus_endpoint: Option<SocketAddr>,
+    api_endpoint: Option<SocketAddr>,
+) -> Result<(), Error> {
+    let config = config.validate()?;
+
     if let Some(addr) = prometheus_endpoint {
         metrics_exporter_prometheus::PrometheusBuilder::new()
             .listen_address(addr)
@@ -116,21 +282,30 @@ async fn start(config: Config, prometheus_endpoint: Option<SocketAddr>) -> Resul
             .unwrap();
     }
 
+    let goals = match &config.goals {
+        Some(goals) => GoalStore::open(&goals.path)?,
+        None => GoalStore::in_memory(),
+    };
+    let audit = match &config.audit {
+        Some(audit) => AuditLog::open(&audit.path),
+        None => AuditLog::disabled(),
+    };
+    let control = ControlState::new();
+    let api_endpoint = api_endpoint.or_else(|| config.api.as_ref().map(|api| api.address));
+
+    let source = config.signal_source().await?;
+
     let Config {
         charging,
-        sgip_credentials,
         tesla_credentials,
+        vehicles: vehicle_configs,
+        site,
+        solar,
+        powerwall,
+        ..
     } = config;
 
     use sgip_ev_charging::tesla;
-    use sgip_signal::SgipSignal;
-
-    tracing::info!("Logging in to SGIP API");
-    let sgip = SgipSignal::login(
-        &sgip_credentials.sgip_username,
-        &sgip_credentials.sgip_password,
-    )
-    .await?;
 
     tracing::info!("Logging in to Tesla API");
     let tesla_token = tesla::AccessToken::login(
@@ -140,40 +315,291 @@ async fn start(config: Config, prometheus_endpoint: Option<SocketAddr>) -> Resul
     )
     .await?;
 
+    let powerwall = match powerwall {
+        Some(powerwall) => {
+            tracing::info!("Fetching energy site info");
+            let sites = tesla_token.energy_sites("sgip-ev-charging").await?;
+            let site = sites
+                .into_iter()
+                .find(|site| {
+                    powerwall
+                        .site_name
+                        .iter()
+                        .all(|name| name == &site.site_name)
+                })
+                .ok_or_else(|| anyhow!("no energy site matching {:?}", powerwall.site_name))?;
+            Some(PowerwallController::connect(powerwall, charging.region, site).await?)
+        }
+        None => None,
+    };
+
+    let default_charging = charging.clone();
+
     tracing::info!("Fetching vehicle info");
-    let vehicle = tesla_token.vehicles("sgip-ev-charging").await?.remove(0);
+    let mut vehicles = tesla_token.vehicles("sgip-ev-charging").await?;
+
+    let vehicles = if vehicle_configs.is_empty() {
+        if vehicles.is_empty() {
+            return Err(anyhow!("no vehicles on the Tesla account"));
+        }
+        vec![(charging, vehicles.remove(0))]
+    } else {
+        vehicle_configs
+            .into_iter()
+            .map(|vehicle_config| {
+                let vehicle = vehicles
+                    .iter()
+                    .find(|v| vehicle_config.selects(&v.vin, &v.display_name))
+                    .cloned()
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "no vehicle matching vin {:?} and display_name {:?}",
+                            vehicle_config.vin,
+                            vehicle_config.display_name
+                        )
+                    })?;
+                tracing::info!(vehicle = %vehicle.name(), "controlling vehicle");
+                Ok((vehicle_config.charging, vehicle))
+            })
+            .collect::<Result<Vec<_>, Error>>()?
+    };
+
+    if let Some(addr) = api_endpoint {
+        // Select each vehicle by both its VIN and display name, so goals
+        // naming it either way are resolved in its time zone.
+        let api_vehicles = vehicles
+            .iter()
+            .map(|(charging, vehicle)| VehicleConfig {
+                vin: Some(vehicle.vin.clone()),
+                display_name: Some(vehicle.display_name.clone()),
+                charging: charging.clone(),
+            })
+            .collect();
+        tokio::spawn(sgip_ev_charging::api::serve(
+            addr,
+            default_charging,
+            api_vehicles,
+            goals.clone(),
+            control.clone(),
+        ));
+    }
+
+    let site = SiteControl {
+        limit: site,
+        solar,
+        powerwall,
+    };
+    sgip_ev_charging::start(vehicles, site, goals, control, audit, source).await
+}
+
+async fn control(api_endpoint: SocketAddr, cmd: ControlCommand) -> Result<(), Error> {
+    let client = reqwest::Client::new();
+    let url = |path| format!("http://{}/{}", api_endpoint, path);
+
+    let (path, new) = match cmd {
+        ControlCommand::Status => {
+            let status: Status = client
+                .get(url("status"))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+            return Ok(());
+        }
+        ControlCommand::Pause { hours, vehicle } => ("pause", NewOverride { hours, vehicle }),
+        ControlCommand::ChargeNow { hours, vehicle } => (
+            "charge-now",
+            NewOverride {
+                hours: Some(hours),
+                vehicle,
+            },
+        ),
+        ControlCommand::Resume { vehicle } => (
+            "resume",
+            NewOverride {
+                hours: None,
+                vehicle,
+            },
+        ),
+    };
+
+    let response = client.post(url(path)).json(&new).send().await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{}: {}", response.status(), response.text().await?));
+    }
+    if path != "resume" {
+        let manual: ManualOverride = response.json().await?;
+        println!("{:?} until {:?}", manual.mode, manual.until);
+    }
+
+    Ok(())
+}
+
+async fn goals(api_endpoint: SocketAddr, cmd: GoalCommand) -> Result<(), Error> {
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/goals", api_endpoint);
+
+    let print = |goal: &OneOffGoal| {
+        println!(
+            "{} {:.0}% {}",
+            goal.time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            goal.charge * 100.,
+            goal.vehicle.as_deref().unwrap_or("all vehicles"),
+        )
+    };
+
+    match cmd {
+        GoalCommand::List => {
+            let goals: Vec<OneOffGoal> = client
+                .get(&url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            goals.iter().for_each(print);
+        }
+        GoalCommand::Add {
+            by,
+            charge,
+            vehicle,
+        } => {
+            let response = client
+                .post(&url)
+                .json(&NewGoal {
+                    by,
+                    charge,
+                    vehicle,
+                })
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(anyhow!("{}: {}", response.status(), response.text().await?));
+            }
+            print(&response.json().await?);
+        }
+        GoalCommand::Remove { time, vehicle } => {
+            let url = format!(
+                "{}/{}",
+                url,
+                time.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+            let response = client
+                .delete(&url)
+                .query(&GoalVehicle { vehicle })
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(anyhow!("{}: {}", response.status(), response.text().await?));
+            }
+        }
+    }
 
-    sgip_ev_charging::start(charging, sgip, vehicle).await
+    Ok(())
 }
 
-async fn simulator(config: Config, backtest_days: usize, prefix: String) -> Result<(), Error> {
+async fn simulator(
+    config: Config,
+    backtest_days: usize,
+    prefix: String,
+    policies: Vec<Policy>,
+    start_date: Option<NaiveDate>,
+    source: DataSource,
+) -> Result<(), Error> {
     let config = config.validate().unwrap();
 
-    for days_ago in 0..std::cmp::min(backtest_days, 21) {
-        // Start at least 2 days ago to ensure data is available
-        let start_day = (Utc::now() - Duration::days(4 + days_ago as i64))
-            .with_timezone(&Pacific)
-            .date();
+    let charging = config.charging.clone();
+
+    // Each run is a (config, output path suffix) pair.
+    let runs = if policies.is_empty() {
+        vec![(config, String::new())]
+    } else {
+        policies
+            .into_iter()
+            .map(|policy| {
+                let mut config = config.clone();
+                config.charging.policy = policy;
+                (config, format!("_{}", policy))
+            })
+            .collect()
+    };
+
+    // Historical SGIP queries only go back a few weeks.
+    let backtest_days = match source {
+        DataSource::Online => std::cmp::min(backtest_days, 21),
+        _ => backtest_days,
+    };
+
+    for day in 0..backtest_days {
+        let start_day = match start_date {
+            Some(start_date) => start_date + Duration::days(day as i64),
+            // Start at least 2 days ago to ensure data is available
+            None => (Utc::now() - Duration::days(4 + day as i64))
+                .with_timezone(&charging.timezone())
+                .date()
+                .naive_local(),
+        };
         tracing::info!(?start_day, "Starting simulation run");
 
         let start_time = NaiveTime::from_hms(0, 0, 0);
-        let start = start_day.and_time(start_time).unwrap().with_timezone(&Utc);
-        let mut sim = Simulator::new(config.clone(), start);
+        let start = charging.local_instant(start_day, start_time);
+
+        for (config, suffix) in &runs {
+            let mut sim = Simulator::new(config.clone(), start).with_source(source.clone());
 
-        sim.run().await.unwrap();
+            sim.run().await.unwrap();
 
-        let output_path = format!("{}_{}.csv", prefix, start_day);
-        tracing::info!(?output_path, "writing simulation data");
+            let output_path = format!("{}{}_{}.csv", prefix, suffix, start_day);
+            tracing::info!(?output_path, "writing simulation data");
 
-        let mut writer = csv::Writer::from_path(&output_path)?;
-        for r in sim.take_records().into_iter() {
-            writer.serialize(r).unwrap();
+            let mut writer = csv::Writer::from_path(&output_path)?;
+            for r in sim.take_records().into_iter() {
+                writer.serialize(r).unwrap();
+            }
         }
     }
 
     Ok(())
 }
 
+fn report(
+    config: Config,
+    period: ReportPeriod,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    csv: Option<PathBuf>,
+    html: Option<PathBuf>,
+) -> Result<(), Error> {
+    let audit = config
+        .audit
+        .ok_or_else(|| anyhow!("no audit log is configured"))?;
+    let charging = config.charging;
+    let midnight = NaiveTime::from_hms(0, 0, 0);
+    let since = since.map(|day| charging.local_instant(day, midnight));
+    let until = until.map(|day| charging.local_instant(day, midnight));
+
+    let mut entries = AuditLog::read(&audit.path)?;
+    entries.retain(|entry| {
+        since.iter().all(|since| entry.time >= *since)
+            && until.iter().all(|until| entry.time < *until)
+    });
+    let rows = sgip_ev_charging::summarize(&entries, charging.timezone(), period);
+
+    if let Some(path) = &html {
+        let title = format!("GHG report by {}", period);
+        File::create(path)?.write_all(sgip_ev_charging::report_html(&rows, &title).as_bytes())?;
+    }
+    match csv {
+        Some(path) => sgip_ev_charging::write_report_csv(&rows, File::create(path)?)?,
+        None if html.is_none() => sgip_ev_charging::write_report_csv(&rows, std::io::stdout())?,
+        None => {}
+    }
+
+    Ok(())
+}
+
 fn merge_csv(
     output: PathBuf,
     inputs: Vec<PathBuf>,
@@ -0,0 +1,349 @@
+use std::ops::Range;
+
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use sgip_signal::{Forecast, Moer};
+
+use crate::{config, intervals::DateIterator, DurationExt, History};
+
+mod planner;
+mod quantile;
+
+pub use planner::{Plan, PlannerPolicy, Slot};
+pub use quantile::QuantilePolicy;
+
+/// A charging decision, together with the reasoning behind it.
+#[derive(Clone, Debug)]
+pub struct Decision {
+    /// The charging power to use during the current interval, in kW, or `0`
+    /// to stop charging.
+    pub power_kw: f64,
+    /// The emissions limit used to make the decision, or `-1` if the decision
+    /// did not depend on emissions.  Under a tariff, this is a limit on the
+    /// combined emissions and cost objective, in thousandths.
+    pub emissions_limit: i64,
+    /// How urgently the vehicle needs charge: the largest proportion of the
+    /// remaining allowed charging time needed to meet any goal.  Used to
+    /// share a site's power budget between vehicles.
+    pub urgency: f64,
+    /// A human-readable explanation of the decision.
+    pub explanation: String,
+    /// Named quantities that went into the decision, exported as gauges by
+    /// the controller.
+    pub factors: Vec<(&'static str, f64)>,
+}
+
+impl Decision {
+    /// A decision not to charge that did not depend on emissions data.
+    pub fn idle(explanation: impl Into<String>) -> Self {
+        Self {
+            power_kw: 0.,
+            emissions_limit: -1,
+            urgency: 0.,
+            explanation: explanation.into(),
+            factors: Vec::new(),
+        }
+    }
+
+    /// Whether to charge during the current interval.
+    pub fn charge(&self) -> bool {
+        self.power_kw > 0.
+    }
+}
+
+/// A strategy for deciding when to charge.
+///
+/// The controller and the simulator call [`ChargePolicy::decide`] once per
+/// 5-minute interval.  Implementations other than the built-in ones can be
+/// passed to [`start_with_policy`](crate::start_with_policy) or
+/// [`Simulator::with_policy`](crate::Simulator::with_policy).
+pub trait ChargePolicy: std::fmt::Debug + Send + Sync {
+    fn decide(
+        &self,
+        config: &config::Charging,
+        now: DateTime<Utc>,
+        soc: f64,
+        history: &History,
+        current: &Moer,
+        forecast: &Forecast,
+    ) -> Decision;
+}
+
+impl config::Policy {
+    /// Construct the built-in policy selected by the config.
+    pub fn build(&self) -> Box<dyn ChargePolicy> {
+        match self {
+            config::Policy::Quantile => Box::new(QuantilePolicy),
+            config::Policy::Planner => Box::new(PlannerPolicy),
+        }
+    }
+}
+
+pub(crate) struct Goal<'c> {
+    pub time: DateTime<Utc>,
+    pub charge: f64,
+    pub config: &'c config::Charging,
+}
+
+impl<'c> std::fmt::Debug for Goal<'c> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Goal")
+            .field("time", &self.time.with_timezone(&self.config.timezone()))
+            .field("charge", &self.charge)
+            .finish()
+    }
+}
+
+impl<'c> Goal<'c> {
+    pub fn available_charging_hours(&self, now: DateTime<Utc>) -> f64 {
+        self.config
+            .allowed_times_during(now..self.time)
+            .map(|range| (range.end - range.start).num_hours_f64())
+            .sum()
+    }
+
+    pub fn required_charging_proportion(&self, now: DateTime<Utc>, present_soc: f64) -> f64 {
+        let charge_kwh = (self.charge - present_soc) * self.config.capacity_kwh;
+        let charge_hours = charge_kwh / self.config.charge_rate_kw;
+        charge_hours / self.available_charging_hours(now)
+    }
+}
+
+impl config::Charging {
+    /// Flex charging: aim to complete the rest of the charging a fixed time
+    /// from now, whatever now is.
+    pub(crate) fn flex_goal(&self, now: DateTime<Utc>) -> Goal<'_> {
+        Goal {
+            time: now + Duration::hours(self.flex_charge_hours),
+            charge: self.max_charge_at(now),
+            config: self,
+        }
+    }
+
+    /// Every recurrence of the daily goals that falls within `range`.
+    pub(crate) fn daily_goals_during(&self, range: Range<DateTime<Utc>>) -> Vec<Goal<'_>> {
+        let start_date = range.start.with_timezone(&self.timezone()).date();
+
+        DateIterator(start_date)
+            .take_while(|date| {
+                self.local_instant(date.naive_local(), NaiveTime::from_hms(0, 0, 0)) < range.end
+            })
+            .flat_map(|date| {
+                let date = date.naive_local();
+                let daily_goals = self.schedule_on(date).daily_goals;
+                daily_goals.iter().map(move |(time, charge)| Goal {
+                    time: self.local_instant(date, *time),
+                    charge: *charge,
+                    config: self,
+                })
+            })
+            .filter(|goal| range.contains(&goal.time))
+            .collect()
+    }
+
+    /// Every goal after `now` that is above `soc`: the flex goal, the daily
+    /// goals, and the one-off goals.
+    pub(crate) fn pending_goals(&self, now: DateTime<Utc>, soc: f64) -> Vec<Goal<'_>> {
+        // The config specifies recurring daily goals, which may differ by
+        // weekday, so the next recurrence of each may be up to a week away.
+        let next_week =
+            now.with_timezone(&self.timezone()).date().naive_local() + Duration::days(8);
+        let daily_goals = self
+            .daily_goals_during(now..self.local_instant(next_week, NaiveTime::from_hms(0, 0, 0)));
+
+        std::iter::once(self.flex_goal(now))
+            .chain(daily_goals)
+            .chain(self.one_off_goals_after(now))
+            .filter(|goal| goal.time > now)
+            .filter(|goal| goal.charge > soc)
+            .collect()
+    }
+
+    /// The pending goal needing the largest proportion of the remaining
+    /// allowed charging time, with that proportion.
+    pub(crate) fn most_urgent_goal(&self, now: DateTime<Utc>, soc: f64) -> Option<(Goal<'_>, f64)> {
+        self.pending_goals(now, soc)
+            .into_iter()
+            .map(|goal| {
+                let proportion = goal.required_charging_proportion(now, soc);
+                (goal, proportion)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    /// The largest proportion of the remaining allowed charging time needed
+    /// to meet any pending goal.
+    pub(crate) fn urgency(&self, now: DateTime<Utc>, soc: f64) -> f64 {
+        self.pending_goals(now, soc)
+            .iter()
+            .map(|goal| goal.required_charging_proportion(now, soc))
+            .fold(0., f64::max)
+    }
+
+    /// The pending one-off goals after `now`.
+    pub(crate) fn one_off_goals_after(&self, now: DateTime<Utc>) -> Vec<Goal<'_>> {
+        self.one_off_goals
+            .iter()
+            .filter(|goal| goal.time > now)
+            .map(|goal| Goal {
+                time: goal.time,
+                charge: goal.charge,
+                config: self,
+            })
+            .collect()
+    }
+
+    /// The charge to stop at, at `time`.  One-off goals may ask for more than
+    /// the max charge, which is then raised until they have passed.
+    pub(crate) fn charge_limit_at(&self, time: DateTime<Utc>) -> f64 {
+        self.one_off_goals
+            .iter()
+            .filter(|goal| goal.time > time)
+            .map(|goal| goal.charge)
+            .fold(self.max_charge_at(time), f64::max)
+    }
+}
+
+impl config::Charging {
+    /// Round a requested charging power to one the charger supports: either
+    /// zero, or between `min_charge_rate_kw` and `charge_rate_kw`.
+    pub(crate) fn supported_power(&self, power_kw: f64) -> f64 {
+        let power_kw = power_kw.max(0.).min(self.charge_rate_kw);
+        if power_kw < self.min_charge_rate_kw {
+            // Round to the nearest supported power.
+            if power_kw < self.min_charge_rate_kw / 2. {
+                0.
+            } else {
+                self.min_charge_rate_kw
+            }
+        } else {
+            power_kw
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone};
+    use sgip_signal::GridRegion;
+
+    #[test]
+    fn policies_across_dst() {
+        let time = |h, m| NaiveTime::from_hms(h, m, 0);
+        let region = GridRegion::CAISO_PGE;
+
+        // Goals and windows at local times that are skipped or repeated.
+        let config = config::Charging {
+            allowed_times: vec![(time(1, 30), time(23, 0))],
+            daily_goals: vec![(time(1, 30), 0.3), (time(2, 30), 0.5)],
+            ..config::Charging::default()
+        };
+
+        for (date, goal_times) in [
+            (
+                NaiveDate::from_ymd(2021, 3, 14),
+                // 01:30 PST, then 02:30 resolves to 03:00 PDT.
+                vec![
+                    Utc.ymd(2021, 3, 14).and_hms(9, 30, 0),
+                    Utc.ymd(2021, 3, 14).and_hms(10, 0, 0),
+                ],
+            ),
+            (
+                NaiveDate::from_ymd(2021, 11, 7),
+                // The first 01:30, in PDT, then 02:30 PST.
+                vec![
+                    Utc.ymd(2021, 11, 7).and_hms(8, 30, 0),
+                    Utc.ymd(2021, 11, 7).and_hms(10, 30, 0),
+                ],
+            ),
+        ] {
+            let start = config.local_instant(date, time(0, 0));
+            let end = config.local_instant(date.succ(), time(0, 0));
+            let goals = config
+                .daily_goals_during(start..end)
+                .iter()
+                .map(|goal| goal.time)
+                .collect::<Vec<_>>();
+            assert_eq!(goals, goal_times);
+
+            let moers = (0..(3 * 24 * 12))
+                .map(|i| Moer {
+                    region,
+                    rate: 0.5 + 0.1 * ((i % 7) as f64),
+                    start: start - Duration::days(1) + Duration::minutes(5 * i),
+                    duration: Duration::minutes(5),
+                })
+                .collect::<Vec<_>>();
+            let forecast = Forecast {
+                region,
+                generated_at: start,
+                data: moers.iter().map(|moer| (moer.start, moer.rate)).collect(),
+            };
+            let history = History::new(region, moers);
+
+            // Neither policy panics at any point across the transition.
+            for policy in &[config::Policy::Quantile, config::Policy::Planner] {
+                let policy = policy.build();
+                let mut now = start;
+                while now < end {
+                    let current = history.at(now).unwrap();
+                    policy.decide(&config, now, 0.2, &history, &current, &forecast);
+                    now = now + Duration::minutes(5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn one_off_goals_raise_the_charge_limit() {
+        let region = GridRegion::CAISO_PGE;
+        let now = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let mut config = config::Charging {
+            allowed_times: vec![(NaiveTime::from_hms(0, 0, 0), NaiveTime::from_hms(23, 0, 0))],
+            ..config::Charging::default()
+        };
+
+        let current = Moer {
+            region,
+            rate: 0.5,
+            start: now,
+            duration: Duration::minutes(5),
+        };
+        let forecast = Forecast {
+            region,
+            generated_at: now,
+            data: (0..(24 * 12))
+                .map(|i| (now + Duration::minutes(5 * i), 0.5))
+                .collect(),
+        };
+        let history = History::new(region, Vec::new());
+        let soc = config.max_charge + 0.05;
+
+        for policy in &[config::Policy::Quantile, config::Policy::Planner] {
+            let policy = policy.build();
+            config.one_off_goals.clear();
+            let decision = policy.decide(&config, now, soc, &history, &current, &forecast);
+            assert!(!decision.charge());
+
+            // A goal above max_charge, soon enough to need charging now.
+            config.one_off_goals = vec![crate::OneOffGoal {
+                time: now + Duration::hours(1),
+                charge: 0.95,
+                vehicle: None,
+            }];
+            assert_eq!(config.charge_limit_at(now), 0.95);
+            let decision = policy.decide(&config, now, soc, &history, &current, &forecast);
+            assert!(decision.charge());
+
+            // Once it is due, max_charge applies again.
+            let due = now + Duration::hours(1);
+            assert_eq!(config.charge_limit_at(due), config.max_charge);
+            let decision = policy.decide(&config, due, soc, &history, &current, &forecast);
+            assert!(!decision.charge());
+            assert_eq!(
+                config.charge_limit_at(now + Duration::hours(2)),
+                config.max_charge
+            );
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sgip_signal::{Forecast, Moer};
+
+use super::{ChargePolicy, Decision, Goal};
+use crate::{config, DurationExt, History};
+
+/// The length of each planning interval, matching the resolution of the MOER data.
+const SLOT_MINUTES: i64 = 5;
+
+/// One interval of a [`Plan`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Slot {
+    /// The start of the interval.
+    pub start: DateTime<Utc>,
+    /// The expected emissions rate during the interval, in kg CO2 / kWh.
+    pub rate: f64,
+    /// The expected value of the objective during the interval, combining
+    /// the emissions rate with the price under a tariff.
+    pub objective: f64,
+    /// The planned charging power during the interval, in kW.
+    pub power_kw: f64,
+}
+
+/// A charging schedule covering every allowed interval from now to the
+/// planning horizon.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Plan {
+    /// The allowed intervals with known emissions rates, in time order.
+    pub slots: Vec<Slot>,
+    /// The charge, in kWh, that could not be scheduled before the deadline of
+    /// the most constrained goal.
+    pub shortfall_kwh: f64,
+}
+
+impl Plan {
+    /// Compute a plan that meets every daily, one-off, and flex goal while
+    /// minimizing expected emissions, or the combined emissions and cost
+    /// objective under a tariff.
+    ///
+    /// Goals are processed in deadline order.  Each goal takes the cleanest
+    /// intervals before its deadline that are not already used by an earlier
+    /// goal, until enough charge is scheduled to meet it.  Because the goal
+    /// constraints are nested, this greedy choice is optimal.  Only the last
+    /// interval chosen for each goal may charge at less than full power.
+    pub fn compute(
+        config: &config::Charging,
+        now: DateTime<Utc>,
+        soc: f64,
+        history: &History,
+        current: &Moer,
+        forecast: &Forecast,
+    ) -> Plan {
+        let step = Duration::minutes(SLOT_MINUTES);
+        let first = Utc.timestamp(
+            now.timestamp() - now.timestamp().rem_euclid(step.num_seconds()),
+            0,
+        );
+
+        let flex_goal = config.flex_goal(now);
+        let one_off_goals = config.one_off_goals_after(now);
+        // Plan at least a day ahead, so that every daily goal is considered
+        // even with a short flex horizon, and far enough to reach every
+        // one-off goal.
+        let horizon = one_off_goals
+            .iter()
+            .map(|goal| goal.time)
+            .chain(std::iter::once(now + Duration::days(1)))
+            .fold(flex_goal.time, std::cmp::max);
+
+        // The SGIP forecasts often get the curve right but offset up or down,
+        // so shift the forecast by its error for the current interval.  This
+        // keeps the current (actual) rate comparable with the forecast rates.
+        let offset = forecast
+            .at(current.start)
+            .map(|predicted| current.rate - predicted.rate)
+            .unwrap_or(0.);
+
+        let allowed = config
+            .allowed_times_during(first..horizon)
+            .collect::<Vec<_>>();
+        let mut slots = Vec::new();
+        let mut start = first;
+        while start < horizon {
+            if allowed.iter().any(|range| range.contains(&start)) {
+                let rate = if start == first {
+                    Some(current.rate)
+                } else {
+                    // Past the end of the forecast, assume the same rate as
+                    // the previous day.
+                    forecast
+                        .at(start)
+                        .map(|predicted| predicted.rate + offset)
+                        .or_else(|| history.at(start - Duration::days(1)).map(|m| m.rate))
+                };
+                if let Some(rate) = rate {
+                    slots.push(Slot {
+                        start,
+                        rate,
+                        objective: config.objective(start, rate),
+                        power_kw: 0.,
+                    });
+                }
+            }
+            start = start + step;
+        }
+
+        let mut goals = std::iter::once(flex_goal)
+            .chain(config.daily_goals_during(now..horizon))
+            .chain(one_off_goals)
+            .filter(|goal| goal.time > now)
+            .collect::<Vec<Goal>>();
+        goals.sort_by_key(|goal| goal.time);
+
+        let slot_hours = step.num_hours_f64();
+        let mut shortfall_kwh = 0.0f64;
+        for goal in goals {
+            // Charging for the goal ends in the slot before it is due.
+            let charge = goal.charge.min(config.charge_limit_at(goal.time - step));
+            let required_kwh = (charge - soc) * config.capacity_kwh;
+
+            let deadline = slots.partition_point(|slot| slot.start < goal.time);
+            let planned_kwh = slots[..deadline]
+                .iter()
+                .map(|slot| slot.power_kw * slot_hours)
+                .sum::<f64>();
+            let mut needed_kwh = required_kwh - planned_kwh;
+
+            let mut candidates = (0..deadline)
+                .filter(|&i| slots[i].power_kw < config.charge_rate_kw)
+                .collect::<Vec<_>>();
+            // Prefer earlier intervals when rates are equal.
+            candidates.sort_by(|&a, &b| {
+                slots[a]
+                    .objective
+                    .partial_cmp(&slots[b].objective)
+                    .unwrap()
+                    .then(a.cmp(&b))
+            });
+
+            for &i in &candidates {
+                // Allow for rounding error in the accumulated energy.
+                if needed_kwh <= 1e-9 {
+                    break;
+                }
+                let slot = &mut slots[i];
+                // Rounding up to the charger's minimum power only ever
+                // schedules more charge, so the goal is still met.
+                let power_kw = (slot.power_kw + needed_kwh / slot_hours)
+                    .max(config.min_charge_rate_kw)
+                    .min(config.charge_rate_kw);
+                needed_kwh -= (power_kw - slot.power_kw) * slot_hours;
+                slot.power_kw = power_kw;
+            }
+            if needed_kwh > 1e-9 {
+                tracing::warn!(?goal, "goal cannot be met");
+                shortfall_kwh = shortfall_kwh.max(needed_kwh);
+            }
+        }
+
+        Plan {
+            slots,
+            shortfall_kwh,
+        }
+    }
+
+    /// Returns the planned charging power at `time`, in kW.
+    pub fn power_at(&self, time: DateTime<Utc>) -> f64 {
+        let step = Duration::minutes(SLOT_MINUTES);
+        self.slots
+            .iter()
+            .find(|slot| (slot.start..(slot.start + step)).contains(&time))
+            .map(|slot| slot.power_kw)
+            .unwrap_or(0.)
+    }
+
+    /// The total charge scheduled by the plan, in kWh.
+    pub fn energy_kwh(&self) -> f64 {
+        let step = Duration::minutes(SLOT_MINUTES);
+        self.slots.iter().map(|slot| slot.power_kw).sum::<f64>() * step.num_hours_f64()
+    }
+
+    /// The highest objective the plan charges at, in thousandths, or `-1` if
+    /// the plan does not charge.  Without a tariff, this is the highest
+    /// emissions rate in g CO2 / kWh.
+    pub fn emissions_limit(&self) -> i64 {
+        self.slots
+            .iter()
+            .filter(|slot| slot.power_kw > 0.)
+            .map(|slot| (slot.objective * 1000.) as i64)
+            .max()
+            .unwrap_or(-1)
+    }
+}
+
+/// A charging policy that plans the whole horizon ahead and follows the plan,
+/// replanning every interval.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlannerPolicy;
+
+impl ChargePolicy for PlannerPolicy {
+    #[tracing::instrument(skip(self, config, current, history, forecast))]
+    fn decide(
+        &self,
+        config: &config::Charging,
+        now: DateTime<Utc>,
+        soc: f64,
+        history: &History,
+        current: &Moer,
+        forecast: &Forecast,
+    ) -> Decision {
+        if !config.allowed_at(now) {
+            return Decision::idle("outside of allowed charging times");
+        }
+
+        if soc >= config.charge_limit_at(now) {
+            return Decision::idle("state of charge is at or above max_charge");
+        }
+
+        let plan = Plan::compute(config, now, soc, history, current, forecast);
+        let power_kw = plan.power_at(now);
+        let emissions_limit = plan.emissions_limit();
+        let energy_kwh = plan.energy_kwh();
+
+        tracing::info!(
+            ?soc,
+            ?energy_kwh,
+            ?plan.shortfall_kwh,
+            ?emissions_limit,
+            current_rate = ?current.rate,
+            ?power_kw,
+        );
+
+        Decision {
+            power_kw,
+            emissions_limit,
+            urgency: config.urgency(now, soc),
+            explanation: format!(
+                "charging at {:.2} kW in current interval of a plan charging {:.2} kWh at rates up to {}",
+                power_kw, energy_kwh, emissions_limit,
+            ),
+            factors: vec![
+                ("vehicle_soc", soc),
+                ("charge_power_kw", power_kw),
+                ("plan_energy_kwh", energy_kwh),
+                ("plan_shortfall_kwh", plan.shortfall_kwh),
+                ("charge_emissions_limit", emissions_limit as f64 / 1000.),
+                ("emissions_current", current.rate),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, Timelike};
+    use chrono_tz::US::Pacific;
+    use sgip_signal::GridRegion;
+
+    fn config() -> config::Charging {
+        config::Charging {
+            allowed_times: vec![(NaiveTime::from_hms(0, 0, 0), NaiveTime::from_hms(23, 0, 0))],
+            capacity_kwh: 60.,
+            charge_rate_kw: 6.,
+            max_charge: 0.6,
+            flex_charge_hours: 18,
+            daily_goals: vec![(NaiveTime::from_hms(8, 0, 0), 0.55)],
+            ..config::Charging::default()
+        }
+    }
+
+    #[test]
+    fn plan_meets_earlier_goal_before_cleaner_later_slots() {
+        let config = config();
+        let now = Pacific.ymd(2021, 3, 1).and_hms(0, 0, 0).with_timezone(&Utc);
+        let region = GridRegion::CAISO_PGE;
+
+        // Emissions are dirty overnight and clean from 10:00 onwards.
+        let forecast = Forecast {
+            region,
+            generated_at: now,
+            data: (0..(24 * 12))
+                .map(|i| {
+                    let time = now + Duration::minutes(5 * i);
+                    let rate = if time.with_timezone(&Pacific).hour() < 10 {
+                        0.9 + (i as f64) * 1e-4
+                    } else {
+                        0.3
+                    };
+                    (time, rate)
+                })
+                .collect(),
+        };
+        let current = forecast.at(now).unwrap();
+        let history = History::new(region, Vec::new());
+
+        let plan = Plan::compute(&config, now, 0.5, &history, &current, &forecast);
+        let goal_time = Pacific.ymd(2021, 3, 1).and_hms(8, 0, 0).with_timezone(&Utc);
+
+        // 0.05 * 60 kWh at 6 kW is half an hour before the 08:00 goal, taken
+        // from the cleanest (earliest) overnight intervals.
+        let before_goal = plan
+            .slots
+            .iter()
+            .filter(|slot| slot.power_kw > 0. && slot.start < goal_time)
+            .collect::<Vec<_>>();
+        assert_eq!(before_goal.len(), 6);
+        assert_eq!(before_goal[0].start, now);
+
+        // The remaining 0.05 for the flex goal uses the clean afternoon.
+        let after_goal = plan
+            .slots
+            .iter()
+            .filter(|slot| slot.power_kw > 0. && slot.start >= goal_time)
+            .collect::<Vec<_>>();
+        assert_eq!(after_goal.len(), 6);
+        assert!(after_goal.iter().all(|slot| slot.rate < 0.5));
+
+        assert_eq!(plan.shortfall_kwh, 0.);
+        assert_eq!(plan.power_at(now + Duration::minutes(2)), 6.);
+        assert_eq!(plan.emissions_limit(), 900);
+    }
+
+    #[test]
+    fn plan_charges_partial_interval() {
+        let config = config::Charging {
+            min_charge_rate_kw: 1.,
+            ..config()
+        };
+        let now = Pacific.ymd(2021, 3, 1).and_hms(9, 0, 0).with_timezone(&Utc);
+        let region = GridRegion::CAISO_PGE;
+
+        let forecast = Forecast {
+            region,
+            generated_at: now,
+            data: (0..(24 * 12))
+                .map(|i| (now + Duration::minutes(5 * i), 0.5 + (i as f64) * 1e-4))
+                .collect(),
+        };
+        let current = forecast.at(now).unwrap();
+        let history = History::new(region, Vec::new());
+
+        // 0.6 - 0.595 = 0.3 kWh, which is 3.6 kW for one interval.
+        let plan = Plan::compute(&config, now, 0.595, &history, &current, &forecast);
+        let charging = plan
+            .slots
+            .iter()
+            .filter(|slot| slot.power_kw > 0.)
+            .collect::<Vec<_>>();
+        assert_eq!(charging.len(), 1);
+        assert!((charging[0].power_kw - 3.6).abs() < 1e-9);
+        assert!((plan.energy_kwh() - 0.3).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,157 @@
+use chrono::{DateTime, Duration, Utc};
+use sgip_signal::{Forecast, Moer};
+
+use super::{ChargePolicy, Decision};
+use crate::{config, ForecastExt, History};
+
+/// The default charging policy.
+///
+/// Selects the most binding goal, works out what proportion of the remaining
+/// allowed charging time is needed to meet it, and charges whenever the
+/// current emissions rate falls below the corresponding quantile of the
+/// expected emissions over that time.  Under a tariff, the policy compares
+/// the combined emissions and cost objective instead.
+///
+/// With a nonzero `charge_ramp`, the charging power instead ramps down
+/// linearly across a band of quantiles centered on that quantile, so that
+/// charging is spread over moderately clean intervals while delivering the
+/// same expected energy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuantilePolicy;
+
+impl ChargePolicy for QuantilePolicy {
+    #[tracing::instrument(skip(self, config, current, history, forecast))]
+    fn decide(
+        &self,
+        config: &config::Charging,
+        now: DateTime<Utc>,
+        soc: f64,
+        history: &History,
+        current: &Moer,
+        forecast: &Forecast,
+    ) -> Decision {
+        // Don't charge outside of allowed times.
+        if !config.allowed_at(now) {
+            return Decision::idle("outside of allowed charging times");
+        }
+
+        // Don't charge if the state of charge is bigger than the maximum.
+        if soc >= config.charge_limit_at(now) {
+            return Decision::idle("state of charge is at or above max_charge");
+        }
+
+        let timezone = config.timezone();
+        let mut goals = config.pending_goals(now, soc);
+
+        // Choose the goal with the largest required charging proportion.
+        goals.sort_by(|a, b| {
+            let a_req = a.required_charging_proportion(now, soc);
+            let b_req = b.required_charging_proportion(now, soc);
+
+            a_req.partial_cmp(&b_req).unwrap()
+        });
+        tracing::info!(?goals);
+        let goal = match goals.pop() {
+            Some(goal) => goal,
+            None => return Decision::idle("no pending goals"),
+        };
+        tracing::info!(?goal, "selected goal");
+
+        let available_charging_hours = goal.available_charging_hours(now);
+        let required_charging_proportion = goal.required_charging_proportion(now, soc);
+
+        let lookahead = config
+            .allowed_times_during(now..goal.time)
+            .collect::<Vec<_>>();
+
+        // The SGIP forecasts often get the curve right but offset up or down,
+        // which biases the forecast emissions data, so combine the forecast
+        // data for the allowed charging windows with the actual data for the
+        // same windows on previous days.  Past data is priced as if it were
+        // at the time in the window it stands in for.
+        let objective = |time, rate| config.objective(time, rate);
+        let mut emissions = forecast.histogram_over(lookahead.clone(), objective);
+        for offset in [Duration::days(1), Duration::days(2)] {
+            let lookback = lookahead
+                .iter()
+                .map(|std::ops::Range { start, end }| (*start - offset)..(*end - offset))
+                .collect::<Vec<_>>();
+            tracing::debug!(?lookahead, ?lookback);
+            emissions +=
+                history.histogram_over(lookback, |time, rate| objective(time + offset, rate));
+        }
+
+        // Ensure that the current emissions rate is included in the histogram,
+        // so that the 100th-percentile value of the histogram is >= the current
+        // rate.  This means that if charge_time_proportion >= 1, we're sure to
+        // charge continuously until the charge target is met.
+        let current_rate = (objective(now, current.rate) * 1000.) as u64;
+        emissions += current_rate;
+
+        let emissions_limit = emissions.value_at_quantile(required_charging_proportion);
+        let power_fraction = if required_charging_proportion >= 1. {
+            1.
+        } else if config.charge_ramp > 0. {
+            let current_quantile = emissions.quantile_below(current_rate);
+            let ramp_end = required_charging_proportion + config.charge_ramp / 2.;
+            ((ramp_end - current_quantile) / config.charge_ramp).clamp(0., 1.)
+        } else if current_rate <= emissions_limit {
+            1.
+        } else {
+            0.
+        };
+        let power_kw = config.supported_power(power_fraction * config.charge_rate_kw);
+
+        // Finally, do tracing and logging of the factors for the decision.
+
+        tracing::info!(
+            now = ?now.with_timezone(&timezone),
+            goal.time = ?goal.time.with_timezone(&timezone),
+            ?soc,
+            ?goal.charge,
+            ?required_charging_proportion,
+            ?emissions_limit,
+            ?current_rate,
+            ?power_kw,
+        );
+
+        let g_to_kg = |g: u64| (g as f64) / 1000.;
+        let emissions_quantile = |q: f64| g_to_kg(emissions.value_at_quantile(q));
+
+        Decision {
+            power_kw,
+            emissions_limit: emissions_limit as i64,
+            urgency: required_charging_proportion,
+            explanation: format!(
+                "charging at {:.2} kW: current rate {} {} limit {} at quantile {:.3} for goal {:.2} at {}",
+                power_kw,
+                current_rate,
+                if current_rate <= emissions_limit {
+                    "<="
+                } else {
+                    ">"
+                },
+                emissions_limit,
+                required_charging_proportion,
+                goal.charge,
+                goal.time.with_timezone(&timezone),
+            ),
+            factors: vec![
+                ("vehicle_soc", soc),
+                ("charge_available_hours", available_charging_hours),
+                ("charge_required_proportion", required_charging_proportion),
+                ("charge_goal", goal.charge),
+                ("charge_power_kw", power_kw),
+                ("charge_emissions_min", emissions_quantile(0.00)),
+                ("charge_emissions_q10", emissions_quantile(0.10)),
+                ("charge_emissions_q25", emissions_quantile(0.25)),
+                ("charge_emissions_q50", emissions_quantile(0.50)),
+                ("charge_emissions_q75", emissions_quantile(0.75)),
+                ("charge_emissions_q90", emissions_quantile(0.90)),
+                ("charge_emissions_max", emissions_quantile(1.00)),
+                ("charge_emissions_limit", g_to_kg(emissions_limit)),
+                ("emissions_current", current.rate),
+            ],
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use anyhow::Error;
+use chrono::{DateTime, Duration, Utc};
+use sgip_signal::{Forecast, GridRegion, Moer};
+use tracing::Instrument;
+
+use crate::{
+    config, controller::RegionSignal, tesla::EnergySite, AuditCommand, AuditEntry, AuditLog,
+    ForecastExt, History, SignalSource,
+};
+
+/// What a Powerwall should do during an interval.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dispatch {
+    /// Charge from the grid.
+    Charge,
+    /// Neither charge from the grid nor discharge.
+    Hold,
+    /// Discharge to cover the home's load.
+    Discharge,
+}
+
+impl Dispatch {
+    /// The backup reserve that makes the battery do this in self-consumption
+    /// mode, given its current charge in percent.
+    pub fn backup_reserve_percent(
+        self,
+        config: &config::Powerwall,
+        percentage_charged: f64,
+    ) -> u32 {
+        match self {
+            Dispatch::Charge => 100,
+            Dispatch::Hold => {
+                (percentage_charged.round() as u32).clamp(config.min_reserve_percent, 100)
+            }
+            Dispatch::Discharge => config.min_reserve_percent,
+        }
+    }
+}
+
+/// A dispatch decision, together with the reasoning behind it.
+#[derive(Clone, Debug)]
+pub struct PowerwallDecision {
+    pub dispatch: Dispatch,
+    /// The limit the current emissions crossed: the charging limit when
+    /// charging, the discharging limit when discharging, or `-1` when
+    /// holding.
+    pub emissions_limit: i64,
+    pub explanation: String,
+    /// Named quantities that went into the decision, exported as gauges.
+    pub factors: Vec<(&'static str, f64)>,
+}
+
+impl config::Powerwall {
+    /// Decide what the battery should do now, given its state of charge as a
+    /// fraction and its capacity in kWh.
+    ///
+    /// As for vehicles, the proportion of the horizon needed to fully charge
+    /// the battery selects a quantile of the expected emissions, and the
+    /// battery charges while the current rate is below it.  Likewise, the
+    /// proportion needed to discharge it to the minimum reserve selects a
+    /// quantile from the top, and the battery discharges while the current
+    /// rate is above that.
+    pub fn decide(
+        &self,
+        now: DateTime<Utc>,
+        soc: f64,
+        capacity_kwh: f64,
+        history: &History,
+        current: &Moer,
+        forecast: &Forecast,
+    ) -> PowerwallDecision {
+        let horizon = now..(now + Duration::hours(self.horizon_hours));
+        // Combine the forecast with the actual data for the same times on
+        // previous days, as the forecasts are often offset.
+        let lookback = [Duration::days(1), Duration::days(2)]
+            .iter()
+            .map(|offset| (horizon.start - *offset)..(horizon.end - *offset))
+            .collect::<Vec<_>>();
+        let mut emissions = history.histogram_over(lookback, |_, rate| rate)
+            + forecast.histogram_over(vec![horizon], |_, rate| rate);
+        let current_rate = (current.rate * 1000.) as u64;
+        emissions += current_rate;
+
+        let hours = self.horizon_hours as f64;
+        let min_soc = self.min_reserve_percent as f64 / 100.;
+        let charge_proportion =
+            ((1. - soc) * capacity_kwh / self.charge_power_kw / hours).clamp(0., 1.);
+        let discharge_proportion =
+            ((soc - min_soc) * capacity_kwh / self.discharge_power_kw / hours).clamp(0., 1.);
+        let charge_limit = emissions.value_at_quantile(charge_proportion);
+        let discharge_limit = emissions.value_at_quantile(1. - discharge_proportion);
+
+        let dispatch = if charge_proportion > 0.
+            && current_rate <= charge_limit
+            && current_rate < discharge_limit
+        {
+            Dispatch::Charge
+        } else if discharge_proportion > 0.
+            && current_rate >= discharge_limit
+            && current_rate > charge_limit
+        {
+            Dispatch::Discharge
+        } else {
+            Dispatch::Hold
+        };
+
+        tracing::info!(
+            ?soc,
+            ?charge_proportion,
+            ?discharge_proportion,
+            ?charge_limit,
+            ?discharge_limit,
+            ?current_rate,
+            ?dispatch,
+        );
+
+        let g_to_kg = |g: u64| (g as f64) / 1000.;
+        PowerwallDecision {
+            dispatch,
+            emissions_limit: match dispatch {
+                Dispatch::Charge => charge_limit as i64,
+                Dispatch::Hold => -1,
+                Dispatch::Discharge => discharge_limit as i64,
+            },
+            explanation: format!(
+                "{:?}: current rate {}, charging below {} at quantile {:.3}, discharging above {} at quantile {:.3}",
+                dispatch,
+                current_rate,
+                charge_limit,
+                charge_proportion,
+                discharge_limit,
+                1. - discharge_proportion,
+            ),
+            factors: vec![
+                ("powerwall_soc", soc),
+                ("powerwall_charge_proportion", charge_proportion),
+                ("powerwall_discharge_proportion", discharge_proportion),
+                ("powerwall_charge_limit", g_to_kg(charge_limit)),
+                ("powerwall_discharge_limit", g_to_kg(discharge_limit)),
+            ],
+        }
+    }
+}
+
+/// A Powerwall dispatched by the emissions signal, alongside the vehicles
+/// under charge control.
+///
+/// Its backup reserve is set whenever the dispatch decision changes, and
+/// every decision is recorded in the audit log.
+pub struct PowerwallController {
+    config: config::Powerwall,
+    region: GridRegion,
+    site: EnergySite,
+    /// The dispatch last applied to the site.
+    last: Option<Dispatch>,
+}
+
+impl PowerwallController {
+    /// Prepare to control `site` using emissions data for `region`, putting
+    /// it in self-consumption mode.
+    pub async fn connect(
+        config: config::Powerwall,
+        region: GridRegion,
+        site: EnergySite,
+    ) -> Result<Self, Error> {
+        let span = tracing::info_span!("energy_site", name = %site.name());
+        async move {
+            let info = site.site_info().await?;
+            tracing::info!(?info, "site info");
+            if info.default_real_mode != "self_consumption" {
+                let rsp = site.set_operation_mode("self_consumption").await;
+                tracing::info!(?rsp, "set self-consumption mode");
+                rsp?;
+            }
+            Ok(Self {
+                config,
+                region,
+                site,
+                last: None,
+            })
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// The region whose emissions data dispatches the battery.
+    pub(crate) fn region(&self) -> GridRegion {
+        self.region
+    }
+
+    /// How far back the emissions history must go.
+    pub(crate) fn lookback(&self) -> Duration {
+        Duration::days(2) + Duration::hours(self.config.horizon_hours)
+    }
+
+    /// Decide the dispatch for the current interval, and set the backup
+    /// reserve if it changed.
+    pub(crate) async fn step(
+        &mut self,
+        signal: &mut RegionSignal,
+        source: &mut dyn SignalSource,
+        audit: &AuditLog,
+    ) -> Result<(), Error> {
+        let span = tracing::info_span!("energy_site", name = %self.site.name());
+        async move {
+            let (dispatch, percent, mut entry) =
+                dispatch(&self.config, &self.site, signal, source).await?;
+            if self.last != Some(dispatch) {
+                let rsp = self.site.set_backup_reserve(percent).await;
+                tracing::info!(?rsp, ?percent, "set backup reserve");
+                entry.commands.push(AuditCommand::new(
+                    format!("set_backup_reserve {}", percent),
+                    &rsp,
+                ));
+                // Retry next interval if the command failed.
+                self.last = rsp.ok().map(|()| dispatch);
+            } else {
+                tracing::info!("dispatch unchanged");
+            }
+            audit.record(&entry);
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Decide the dispatch and backup reserve for the current interval, with an
+/// audit entry for the decision.
+async fn dispatch(
+    powerwall: &config::Powerwall,
+    site: &EnergySite,
+    signal: &mut RegionSignal,
+    source: &mut dyn SignalSource,
+) -> Result<(Dispatch, u32, AuditEntry), Error> {
+    signal.prepare(Utc::now(), source).await?;
+    let (history, current, forecast) = signal.data();
+
+    let status = site.live_status().await?;
+    tracing::debug!(?status);
+    let soc = status.percentage_charged / 100.;
+    let capacity_kwh = status.total_pack_energy / 1000.;
+
+    let now = Utc::now();
+    let decision = powerwall.decide(now, soc, capacity_kwh, history, current, forecast);
+    tracing::info!(explanation = %decision.explanation, "dispatch decision");
+    for (name, value) in &decision.factors {
+        metrics::gauge!(*name, *value);
+    }
+    metrics::gauge!("powerwall_battery_power_kw", status.battery_power / 1000.);
+
+    let percent = decision
+        .dispatch
+        .backup_reserve_percent(powerwall, status.percentage_charged);
+    let entry = AuditEntry {
+        time: now,
+        unit: site.name(),
+        region: current.region,
+        moer: current.rate,
+        moer_start: current.start,
+        forecast_generated_at: Some(forecast.generated_at),
+        emissions_limit: decision.emissions_limit,
+        // The battery's power is positive when discharging.
+        power_kw: -status.battery_power / 1000.,
+        interval_minutes: 5,
+        soc,
+        explanation: decision.explanation,
+        commands: Vec::new(),
+    };
+    Ok((decision.dispatch, percent, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn charge_clean_discharge_dirty() {
+        let config = config::Powerwall {
+            site_name: None,
+            min_reserve_percent: 20,
+            charge_power_kw: 5.,
+            discharge_power_kw: 2.,
+            horizon_hours: 24,
+        };
+        let region = GridRegion::CAISO_PGE;
+        let now = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+
+        // Rates rise steadily over the day, from 0.2 to about 0.8.
+        let forecast = Forecast {
+            region,
+            generated_at: now,
+            data: (0..(24 * 12))
+                .map(|i| (now + Duration::minutes(5 * i), 0.2 + (i as f64) * 2e-3))
+                .collect(),
+        };
+        let history = History::new(region, Vec::new());
+        let decide = |soc, rate| {
+            let current = Moer {
+                region,
+                rate,
+                start: now,
+                duration: Duration::minutes(5),
+            };
+            config
+                .decide(now, soc, 13.5, &history, &current, &forecast)
+                .dispatch
+        };
+
+        // Half full, 13.5 * 0.5 / 5 kW is 1.35 hours of charging, so charge
+        // in the cleanest ~6% of the day, and 13.5 * 0.3 / 2 kW is about 2
+        // hours of discharging, so discharge in the dirtiest ~8%.
+        assert_eq!(decide(0.5, 0.21), Dispatch::Charge);
+        assert_eq!(decide(0.5, 0.5), Dispatch::Hold);
+        assert_eq!(decide(0.5, 0.79), Dispatch::Discharge);
+
+        // A full battery doesn't charge, and an empty one doesn't discharge.
+        assert_eq!(decide(1.0, 0.21), Dispatch::Hold);
+        assert_eq!(decide(0.2, 0.79), Dispatch::Hold);
+
+        assert_eq!(Dispatch::Hold.backup_reserve_percent(&config, 54.6), 55);
+        assert_eq!(Dispatch::Hold.backup_reserve_percent(&config, 10.), 20);
+    }
+}
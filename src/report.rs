@@ -0,0 +1,230 @@
+use std::{collections::BTreeMap, fmt, io, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use chrono::Datelike;
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use crate::AuditEntry;
+
+/// How to group the rows of a GHG report.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReportPeriod {
+    Month,
+    Year,
+}
+
+impl FromStr for ReportPeriod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "month" => Ok(ReportPeriod::Month),
+            "year" => Ok(ReportPeriod::Year),
+            _ => Err(anyhow!(
+                "unknown report period {}, expected month or year",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ReportPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReportPeriod::Month => "month",
+            ReportPeriod::Year => "year",
+        })
+    }
+}
+
+/// The GHG summary for one vehicle or energy site over one period.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ReportRow {
+    /// The local year, or year and month, such as `2021` or `2021-03`.
+    pub period: String,
+    pub unit: String,
+    /// The number of intervals with a recorded decision.
+    pub decisions: usize,
+    /// The number of commands sent.
+    pub commands: usize,
+    pub charged_kwh: f64,
+    pub discharged_kwh: f64,
+    /// The MOER while charging, weighted by the energy charged, in kg CO2 /
+    /// kWh, or `None` if nothing was charged.
+    pub charge_weighted_moer: Option<f64>,
+    /// The MOER averaged over every recorded interval, in kg CO2 / kWh, to
+    /// compare against the charge-weighted MOER.
+    pub average_moer: f64,
+    /// The emissions caused by charging, net of those avoided by
+    /// discharging, in kg CO2.
+    pub emissions_kg: f64,
+}
+
+/// Summarize audit log entries by unit and by local period in `timezone`.
+///
+/// Each entry counts as charging or discharging at its recorded power for
+/// its whole interval.
+pub fn summarize(entries: &[AuditEntry], timezone: Tz, period: ReportPeriod) -> Vec<ReportRow> {
+    let mut rows = BTreeMap::<(String, String), ReportRow>::new();
+    let mut moer_sums = BTreeMap::<(String, String), f64>::new();
+
+    for entry in entries {
+        let local = entry.time.with_timezone(&timezone);
+        let key = match period {
+            ReportPeriod::Month => format!("{}-{:02}", local.year(), local.month()),
+            ReportPeriod::Year => local.year().to_string(),
+        };
+        let key = (key, entry.unit.clone());
+        let row = rows.entry(key.clone()).or_insert_with(|| ReportRow {
+            period: key.0.clone(),
+            unit: key.1.clone(),
+            decisions: 0,
+            commands: 0,
+            charged_kwh: 0.,
+            discharged_kwh: 0.,
+            charge_weighted_moer: None,
+            average_moer: 0.,
+            emissions_kg: 0.,
+        });
+
+        let energy_kwh = entry.power_kw * entry.interval_minutes as f64 / 60.;
+        row.decisions += 1;
+        row.commands += entry.commands.len();
+        if energy_kwh > 0. {
+            row.charged_kwh += energy_kwh;
+            *row.charge_weighted_moer.get_or_insert(0.) += energy_kwh * entry.moer;
+        } else {
+            row.discharged_kwh -= energy_kwh;
+        }
+        row.emissions_kg += energy_kwh * entry.moer;
+        *moer_sums.entry(key).or_insert(0.) += entry.moer;
+    }
+
+    rows.into_iter()
+        .map(|(key, mut row)| {
+            row.charge_weighted_moer = row
+                .charge_weighted_moer
+                .map(|emissions| emissions / row.charged_kwh);
+            row.average_moer = moer_sums[&key] / row.decisions as f64;
+            row
+        })
+        .collect()
+}
+
+/// Write a report as CSV.
+pub fn write_report_csv(rows: &[ReportRow], writer: impl io::Write) -> Result<(), Error> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Render a report as a standalone HTML page.
+pub fn report_html(rows: &[ReportRow], title: &str) -> String {
+    let escape = |s: &str| {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n\
+         <style>table {{ border-collapse: collapse; }} th, td {{ border: 1px solid #999; padding: 4px 8px; }} \
+         td {{ text-align: right; }}</style>\n</head>\n<body>\n<h1>{0}</h1>\n<table>\n\
+         <tr><th>Period</th><th>Unit</th><th>Decisions</th><th>Commands</th>\
+         <th>Charged (kWh)</th><th>Discharged (kWh)</th><th>Charge-weighted MOER (kg/kWh)</th>\
+         <th>Average MOER (kg/kWh)</th><th>Net emissions (kg CO2)</th></tr>\n",
+        escape(title)
+    );
+    for row in rows {
+        html += &format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.2}</td><td>{:.2}</td>\
+             <td>{}</td><td>{:.3}</td><td>{:.2}</td></tr>\n",
+            escape(&row.period),
+            escape(&row.unit),
+            row.decisions,
+            row.commands,
+            row.charged_kwh,
+            row.discharged_kwh,
+            row.charge_weighted_moer
+                .map(|moer| format!("{:.3}", moer))
+                .unwrap_or_else(|| "-".to_string()),
+            row.average_moer,
+            row.emissions_kg,
+        );
+    }
+    html += "</table>\n</body>\n</html>\n";
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AuditCommand, AuditLog};
+    use chrono::{Duration, TimeZone, Utc};
+    use sgip_signal::GridRegion;
+
+    #[test]
+    fn monthly_summary_from_log() {
+        let dir = std::env::temp_dir().join(format!("sgip-report-test-{}", std::process::id()));
+        // The log is appended to, so start from an empty one.
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("audit.jsonl");
+        let log = AuditLog::open(&path);
+
+        // An hour at 6 kW and 0.2, then an hour idle at 0.8, on the last day
+        // of March, local time, and one interval in April.
+        let start = Tz::US__Pacific
+            .ymd(2021, 3, 31)
+            .and_hms(22, 0, 0)
+            .with_timezone(&Utc);
+        for i in 0..25 {
+            let charging = i < 12;
+            log.record(&AuditEntry {
+                time: start + Duration::minutes(5 * i),
+                unit: "Red".to_string(),
+                region: GridRegion::CAISO_PGE,
+                moer: if charging { 0.2 } else { 0.8 },
+                moer_start: start + Duration::minutes(5 * i),
+                forecast_generated_at: None,
+                emissions_limit: 500,
+                power_kw: if charging { 6. } else { 0. },
+                interval_minutes: 5,
+                soc: 0.5,
+                explanation: String::new(),
+                commands: if i == 0 || i == 12 {
+                    vec![AuditCommand::new("charge_start", &Ok(()))]
+                } else {
+                    Vec::new()
+                },
+            });
+        }
+
+        let entries = AuditLog::read(&path).unwrap();
+        assert_eq!(entries.len(), 25);
+        let rows = summarize(&entries, Tz::US__Pacific, ReportPeriod::Month);
+        assert_eq!(rows.len(), 2);
+
+        let march = &rows[0];
+        assert_eq!(march.period, "2021-03");
+        assert_eq!(march.decisions, 24);
+        assert_eq!(march.commands, 2);
+        assert!((march.charged_kwh - 6.).abs() < 1e-9);
+        assert!((march.charge_weighted_moer.unwrap() - 0.2).abs() < 1e-9);
+        assert!((march.average_moer - 0.5).abs() < 1e-9);
+        assert!((march.emissions_kg - 1.2).abs() < 1e-9);
+
+        let april = &rows[1];
+        assert_eq!(april.period, "2021-04");
+        assert_eq!(april.charge_weighted_moer, None);
+
+        let html = report_html(&rows, "Red <GHG>");
+        assert!(html.contains("<title>Red &lt;GHG&gt;</title>"));
+        assert!(html.contains("<td>2021-04</td><td>Red</td><td>1</td><td>0</td>"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
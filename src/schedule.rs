@@ -0,0 +1,86 @@
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc};
+
+use crate::config;
+
+/// The charging schedule for one local date, with overrides applied.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DaySchedule<'c> {
+    pub allowed_times: &'c [(NaiveTime, NaiveTime)],
+    pub daily_goals: &'c [(NaiveTime, f64)],
+    pub max_charge: f64,
+}
+
+impl config::Charging {
+    /// The schedule for the local `date`.
+    pub(crate) fn schedule_on(&self, date: NaiveDate) -> DaySchedule<'_> {
+        let by_date = self.overrides.iter().filter(|o| o.dates.contains(&date));
+        let by_weekday = self
+            .overrides
+            .iter()
+            .filter(|o| o.weekdays.contains(&date.weekday()));
+        let matching = by_date.chain(by_weekday).collect::<Vec<_>>();
+
+        DaySchedule {
+            allowed_times: matching
+                .iter()
+                .find_map(|o| o.allowed_times.as_deref())
+                .unwrap_or(&self.allowed_times),
+            daily_goals: matching
+                .iter()
+                .find_map(|o| o.daily_goals.as_deref())
+                .unwrap_or(&self.daily_goals),
+            max_charge: matching
+                .iter()
+                .find_map(|o| o.max_charge)
+                .unwrap_or(self.max_charge),
+        }
+    }
+
+    /// The maximum charge for the local date containing `time`.
+    pub fn max_charge_at(&self, time: DateTime<Utc>) -> f64 {
+        let date = time.with_timezone(&self.timezone()).date().naive_local();
+        self.schedule_on(date).max_charge
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Weekday;
+
+    #[test]
+    fn overrides_fall_through() {
+        let time = |h| NaiveTime::from_hms(h, 0, 0);
+        let charging = config::Charging {
+            overrides: vec![
+                config::Override {
+                    weekdays: vec![Weekday::Sat, Weekday::Sun],
+                    allowed_times: Some(vec![(time(9), time(17))]),
+                    daily_goals: Some(vec![]),
+                    ..config::Override::default()
+                },
+                config::Override {
+                    dates: vec![NaiveDate::from_ymd(2021, 3, 6)],
+                    max_charge: Some(0.95),
+                    ..config::Override::default()
+                },
+            ],
+            ..config::Charging::default()
+        };
+
+        // A Friday uses the defaults.
+        let friday = charging.schedule_on(NaiveDate::from_ymd(2021, 3, 5));
+        assert_eq!(friday.allowed_times, &charging.allowed_times[..]);
+        assert_eq!(friday.daily_goals.len(), 2);
+
+        // The date override only replaces max_charge, so the rest of the
+        // Saturday comes from the weekend override.
+        let saturday = charging.schedule_on(NaiveDate::from_ymd(2021, 3, 6));
+        assert_eq!(saturday.allowed_times, &[(time(9), time(17))]);
+        assert!(saturday.daily_goals.is_empty());
+        assert_eq!(saturday.max_charge, 0.95);
+
+        let sunday = charging.schedule_on(NaiveDate::from_ymd(2021, 3, 7));
+        assert_eq!(sunday.max_charge, charging.max_charge);
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{prelude::*, BufReader},
+    ops::Range,
+    path::Path,
+};
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sgip_signal::{Forecast, GridRegion, Moer, SgipSignal};
+
+use crate::{
+    config, history::MOER_INTERVAL_MINUTES, intervals, watttime::WattTime, Archive, Config,
+};
+
+/// A provider of marginal emissions data.
+///
+/// This covers the operations the controller and simulator need from
+/// [`SgipSignal`], so that other providers, archived data, or local fakes can
+/// be used in its place.
+#[async_trait]
+pub trait SignalSource: Send {
+    /// Fetch the current MOER for `region`.
+    async fn moer(&mut self, region: GridRegion) -> Result<Moer, Error>;
+
+    /// Fetch the current forecast for `region`.
+    async fn forecast(&mut self, region: GridRegion) -> Result<Forecast, Error>;
+
+    /// Fetch the MOERs for `region` starting from `start` until `end`, or
+    /// until now if `end` is `None`.
+    async fn historic_moers(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Moer>, Error>;
+
+    /// Fetch the forecasts for `region` generated between `start` and `end`.
+    async fn historic_forecasts(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Forecast>, Error>;
+}
+
+impl Config {
+    /// Log in to the signal provider selected in the config, recording its
+    /// data in the archive if one is configured.
+    pub async fn signal_source(&self) -> Result<Box<dyn SignalSource>, Error> {
+        let source: Box<dyn SignalSource> = match self.charging.signal {
+            config::Signal::Sgip => {
+                tracing::info!("Logging in to SGIP API");
+                Box::new(
+                    SgipSignal::login(
+                        &self.sgip_credentials.sgip_username,
+                        &self.sgip_credentials.sgip_password,
+                    )
+                    .await?,
+                )
+            }
+            config::Signal::WattTime => {
+                tracing::info!("Logging in to WattTime API");
+                let credentials = self
+                    .watttime_credentials
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("watttime credentials are required"))?;
+                Box::new(
+                    WattTime::login(
+                        &credentials.watttime_username,
+                        &credentials.watttime_password,
+                    )
+                    .await?
+                    .with_region(self.charging.watttime_region.clone()),
+                )
+            }
+        };
+
+        Ok(match &self.archive {
+            Some(archive) => Box::new(Archived::new(source, Archive::open(&archive.path)?)),
+            None => source,
+        })
+    }
+}
+
+#[async_trait]
+impl<S: SignalSource + ?Sized> SignalSource for Box<S> {
+    async fn moer(&mut self, region: GridRegion) -> Result<Moer, Error> {
+        (**self).moer(region).await
+    }
+
+    async fn forecast(&mut self, region: GridRegion) -> Result<Forecast, Error> {
+        (**self).forecast(region).await
+    }
+
+    async fn historic_moers(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Moer>, Error> {
+        (**self).historic_moers(region, start, end).await
+    }
+
+    async fn historic_forecasts(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Forecast>, Error> {
+        (**self).historic_forecasts(region, start, end).await
+    }
+}
+
+#[async_trait]
+impl SignalSource for SgipSignal {
+    async fn moer(&mut self, region: GridRegion) -> Result<Moer, Error> {
+        SgipSignal::moer(self, region).await
+    }
+
+    async fn forecast(&mut self, region: GridRegion) -> Result<Forecast, Error> {
+        SgipSignal::forecast(self, region).await
+    }
+
+    async fn historic_moers(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Moer>, Error> {
+        SgipSignal::historic_moers(self, region, start, end).await
+    }
+
+    async fn historic_forecasts(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Forecast>, Error> {
+        // Historical forecast queries are limited to one day.
+        let mut forecasts = Vec::new();
+        let mut chunk_start = start;
+        while chunk_start < end {
+            let chunk_end = std::cmp::min(chunk_start + Duration::days(1), end);
+            forecasts.extend(
+                SgipSignal::historic_forecasts(self, region, chunk_start, chunk_end).await?,
+            );
+            chunk_start = chunk_end;
+        }
+        Ok(forecasts)
+    }
+}
+
+/// Serves archived data, without network access.
+///
+/// The current MOER and forecast are the latest ones in the archive.
+#[async_trait]
+impl SignalSource for Archive {
+    async fn moer(&mut self, region: GridRegion) -> Result<Moer, Error> {
+        let now = Utc::now();
+        self.moers(region, (now - Duration::days(1))..now)?
+            .pop()
+            .ok_or_else(|| anyhow!("no MOERs for {} archived in the last day", region))
+    }
+
+    async fn forecast(&mut self, region: GridRegion) -> Result<Forecast, Error> {
+        let now = Utc::now();
+        self.forecasts(region, (now - Duration::days(1))..now)?
+            .pop()
+            .ok_or_else(|| anyhow!("no forecasts for {} archived in the last day", region))
+    }
+
+    async fn historic_moers(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Moer>, Error> {
+        self.moers(region, start..end.unwrap_or_else(Utc::now))
+    }
+
+    async fn historic_forecasts(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Forecast>, Error> {
+        self.forecasts(region, start..end)
+    }
+}
+
+/// Wraps another source, recording everything it fetches in an [`Archive`]
+/// and reading historic data from the archive where possible.
+pub struct Archived<S> {
+    source: S,
+    archive: Archive,
+}
+
+impl<S: SignalSource> Archived<S> {
+    pub fn new(source: S, archive: Archive) -> Self {
+        Self { source, archive }
+    }
+}
+
+#[async_trait]
+impl<S: SignalSource> SignalSource for Archived<S> {
+    async fn moer(&mut self, region: GridRegion) -> Result<Moer, Error> {
+        let moer = self.source.moer(region).await?;
+        if let Err(e) = self
+            .archive
+            .record_moers(region, std::slice::from_ref(&moer))
+        {
+            tracing::error!(%e, "failed to archive MOER");
+        }
+        Ok(moer)
+    }
+
+    async fn forecast(&mut self, region: GridRegion) -> Result<Forecast, Error> {
+        let forecast = self.source.forecast(region).await?;
+        if let Err(e) = self
+            .archive
+            .record_forecasts(std::slice::from_ref(&forecast))
+        {
+            tracing::error!(%e, "failed to archive forecast");
+        }
+        Ok(forecast)
+    }
+
+    async fn historic_moers(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Moer>, Error> {
+        let range = start..end.unwrap_or_else(Utc::now);
+        let mut moers = self.archive.moers(region, range.clone())?;
+        let interval = Duration::minutes(MOER_INTERVAL_MINUTES);
+        let gaps = intervals::gaps(moers.iter().map(|m| m.start), range, interval);
+
+        for gap in gaps {
+            tracing::debug!(?gap, "downloading MOERs missing from archive");
+            let fetched = self
+                .source
+                .historic_moers(region, gap.start, Some(gap.end))
+                .await?;
+            self.archive.record_moers(region, &fetched)?;
+            moers.extend(fetched);
+        }
+
+        moers.sort_by_key(|moer| moer.start);
+        moers.dedup_by_key(|moer| moer.start);
+        Ok(moers)
+    }
+
+    async fn historic_forecasts(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Forecast>, Error> {
+        let range = start..end;
+        let mut forecasts = self.archive.forecasts(region, range.clone())?;
+        // Forecasts are generated every 5 minutes.
+        let interval = Duration::minutes(MOER_INTERVAL_MINUTES);
+        let gaps = intervals::gaps(forecasts.iter().map(|f| f.generated_at), range, interval);
+
+        for gap in gaps {
+            tracing::debug!(?gap, "downloading forecasts missing from archive");
+            let fetched = self
+                .source
+                .historic_forecasts(region, gap.start, gap.end)
+                .await?;
+            self.archive.record_forecasts(&fetched)?;
+            forecasts.extend(fetched);
+        }
+
+        forecasts.sort_by_key(|forecast| forecast.generated_at);
+        forecasts.dedup_by_key(|forecast| forecast.generated_at);
+        Ok(forecasts)
+    }
+}
+
+/// Serves MOERs and forecasts held in memory, such as local data dumps or
+/// synthetic test data.
+///
+/// The current MOER and forecast are the latest ones in the data.
+#[derive(Clone, Debug, Default)]
+pub struct FileSource {
+    moers: BTreeMap<DateTime<Utc>, Moer>,
+    forecasts: BTreeMap<DateTime<Utc>, Forecast>,
+}
+
+/// A row of a MOER CSV dump.
+#[derive(Deserialize)]
+struct MoerRow {
+    start: DateTime<Utc>,
+    /// The emissions rate, in kg CO2 / kWh.
+    rate: f64,
+}
+
+impl FileSource {
+    pub fn new(moers: Vec<Moer>, forecasts: Vec<Forecast>) -> Self {
+        Self {
+            moers: moers.into_iter().map(|moer| (moer.start, moer)).collect(),
+            forecasts: forecasts
+                .into_iter()
+                .map(|forecast| (forecast.generated_at, forecast))
+                .collect(),
+        }
+    }
+
+    /// Read MOERs for `region` and forecasts from local dumps.
+    ///
+    /// MOERs are read from a CSV file with `start` and `rate` columns, or from
+    /// a `.json` file holding an array of MOERs in the SGIP API format.
+    /// Forecasts are read from a `.json` file holding an array of SGIP API
+    /// forecast responses, or from a `.jsonl` file with one per line.
+    pub fn open(region: GridRegion, moers: &Path, forecasts: &Path) -> Result<Self, Error> {
+        Ok(Self::new(
+            read_moers(moers, region)?,
+            read_forecasts(forecasts)?,
+        ))
+    }
+
+    fn latest<T>(
+        data: &BTreeMap<DateTime<Utc>, T>,
+        region: GridRegion,
+        region_of: impl Fn(&T) -> GridRegion,
+    ) -> Option<&T> {
+        data.range(..=Utc::now())
+            .rev()
+            .map(|(_, value)| value)
+            .find(|value| region_of(value) == region)
+    }
+
+    fn range<T>(
+        data: &BTreeMap<DateTime<Utc>, T>,
+        region: GridRegion,
+        range: Range<DateTime<Utc>>,
+        region_of: impl Fn(&T) -> GridRegion,
+    ) -> impl Iterator<Item = &T> {
+        data.range(range)
+            .map(|(_, value)| value)
+            .filter(move |value| region_of(value) == region)
+    }
+}
+
+#[async_trait]
+impl SignalSource for FileSource {
+    async fn moer(&mut self, region: GridRegion) -> Result<Moer, Error> {
+        Self::latest(&self.moers, region, |moer| moer.region)
+            .cloned()
+            .ok_or_else(|| anyhow!("no MOERs for {}", region))
+    }
+
+    async fn forecast(&mut self, region: GridRegion) -> Result<Forecast, Error> {
+        Self::latest(&self.forecasts, region, |forecast| forecast.region)
+            .cloned()
+            .ok_or_else(|| anyhow!("no forecasts for {}", region))
+    }
+
+    async fn historic_moers(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Moer>, Error> {
+        let range = start..end.unwrap_or_else(Utc::now);
+        Ok(Self::range(&self.moers, region, range, |moer| moer.region)
+            .cloned()
+            .collect())
+    }
+
+    async fn historic_forecasts(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Forecast>, Error> {
+        Ok(
+            Self::range(&self.forecasts, region, start..end, |forecast| {
+                forecast.region
+            })
+            .cloned()
+            .collect(),
+        )
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("json")
+}
+
+/// Read a MOER dump for `region`.
+fn read_moers(path: &Path, region: GridRegion) -> Result<Vec<Moer>, Error> {
+    if is_json(path) {
+        let moers: Vec<Moer> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        Ok(moers
+            .into_iter()
+            .filter(|moer| moer.region == region)
+            .collect())
+    } else {
+        csv::Reader::from_path(path)?
+            .into_deserialize::<MoerRow>()
+            .map(|row| {
+                let row = row?;
+                Ok(Moer {
+                    region,
+                    rate: row.rate,
+                    start: row.start,
+                    duration: Duration::minutes(MOER_INTERVAL_MINUTES),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Read a forecast dump, as a JSON array or as JSON lines.
+fn read_forecasts(path: &Path) -> Result<Vec<Forecast>, Error> {
+    let reader = BufReader::new(File::open(path)?);
+    if is_json(path) {
+        Ok(serde_json::from_reader(reader)?)
+    } else {
+        reader
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    async fn archived_source_reads_through() {
+        let dir = std::env::temp_dir().join(format!("sgip-signal-test-{}", std::process::id()));
+        let region = GridRegion::CAISO_PGE;
+        let t0 = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let moer = |i: i64| Moer {
+            region,
+            rate: 0.1 * i as f64,
+            start: t0 + Duration::minutes(5 * i),
+            duration: Duration::minutes(5),
+        };
+
+        // Only the first half hour has been archived so far.
+        let archive = Archive::open(&dir).unwrap();
+        archive
+            .record_moers(region, &(0..6).map(moer).collect::<Vec<_>>())
+            .unwrap();
+
+        let upstream = FileSource::new((0..12).map(moer).collect(), Vec::new());
+        let mut source = Archived::new(upstream, archive.clone());
+        let moers = source
+            .historic_moers(region, t0, Some(t0 + Duration::hours(1)))
+            .await
+            .unwrap();
+        assert_eq!(moers.len(), 12);
+
+        // The downloaded half hour was archived, so the archive alone now
+        // serves the whole hour.
+        let mut offline = archive;
+        let moers = offline
+            .historic_moers(region, t0, Some(t0 + Duration::hours(1)))
+            .await
+            .unwrap();
+        assert_eq!(moers.len(), 12);
+        assert_eq!(moers[11].rate, moer(11).rate);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
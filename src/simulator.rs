@@ -1,11 +1,15 @@
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use chrono::{DateTime, Duration, Utc};
-use chrono_tz::US::Pacific;
 use serde::Serialize;
-use sgip_signal::{Forecast, GridRegion, SgipSignal};
-use std::{collections::BTreeMap, fmt, ops::Range};
+use sgip_signal::{Forecast, GridRegion};
+use std::{collections::BTreeMap, fmt, ops::Range, path::PathBuf, sync::Arc};
 
-use crate::{Config, History};
+use crate::{
+    baseline::{Baseline, Charged},
+    stability::Stabilizer,
+    tariff::DemandMeter,
+    Archive, ChargePolicy, Config, FileSource, History, SignalSource,
+};
 
 #[derive(Serialize, Clone, Debug)]
 pub struct Record {
@@ -24,13 +28,72 @@ pub struct Record {
     pub s30_emissions_used: u64,
     pub s50_emissions_used: u64,
     pub s70_emissions_used: u64,
+    pub s10_power_kw: f64,
+    pub s30_power_kw: f64,
+    pub s50_power_kw: f64,
+    pub s70_power_kw: f64,
+    /// How many times each simulated vehicle has started or stopped charging
+    /// so far.
+    pub s10_switches: u32,
+    pub s30_switches: u32,
+    pub s50_switches: u32,
+    pub s70_switches: u32,
+    /// The tariff's energy price, in $ / kWh.
+    pub price: f64,
+    /// The cost of each simulated vehicle's charging during the interval, in
+    /// $, including demand charges on new monthly peaks.
+    pub s10_cost: f64,
+    pub s30_cost: f64,
+    pub s50_cost: f64,
+    pub s70_cost: f64,
+    /// The emissions each simulated vehicle has avoided up to `time`,
+    /// compared with charging at full power from the start, in kg CO2.
+    pub s10_avoided_kg: f64,
+    pub s30_avoided_kg: f64,
+    pub s50_avoided_kg: f64,
+    pub s70_avoided_kg: f64,
+}
+
+/// Where the simulator reads MOERs and forecasts from.
+#[derive(Clone, Debug, Default)]
+pub enum DataSource {
+    /// Download data from the signal provider selected in the config,
+    /// reading through the archive if one is configured.
+    #[default]
+    Online,
+    /// Read data from an archive directory only, without network access.
+    Archive(PathBuf),
+    /// Read data from local dumps, without network access.  See
+    /// [`FileSource::open`] for the supported formats.
+    Files { moers: PathBuf, forecasts: PathBuf },
+}
+
+impl DataSource {
+    /// Open the source for the region and signal provider in `config`.
+    pub async fn open(&self, config: &Config) -> Result<Box<dyn SignalSource>, Error> {
+        Ok(match self {
+            DataSource::Online => config.signal_source().await?,
+            DataSource::Archive(path) => Box::new(Archive::open(path)?),
+            DataSource::Files { moers, forecasts } => {
+                Box::new(FileSource::open(config.charging.region, moers, forecasts)?)
+            }
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Simulator {
     config: Config,
+    policy: Arc<dyn ChargePolicy>,
+    source: DataSource,
     start: DateTime<Utc>,
     records: Vec<Record>,
+    /// Holds decisions steady for the s10, s30, s50, and s70 vehicles.
+    stabilizers: [Stabilizer; 4],
+    /// Tracks demand charges for the s10, s30, s50, and s70 vehicles.
+    meters: [DemandMeter; 4],
+    /// Tracks emissions avoided by the s10, s30, s50, and s70 vehicles.
+    baselines: [Baseline; 4],
 }
 struct F(pub f64, pub usize);
 
@@ -41,13 +104,27 @@ impl std::fmt::Debug for F {
 }
 
 impl Simulator {
+    /// Create a simulator using the policy selected in the config.
     pub fn new(config: Config, start: DateTime<Utc>) -> Self {
+        let policy = config.charging.policy.build().into();
+        Self::with_policy(config, policy, start)
+    }
+
+    /// Create a simulator using a caller-supplied [`ChargePolicy`].
+    pub fn with_policy(
+        config: Config,
+        policy: Arc<dyn ChargePolicy>,
+        start: DateTime<Utc>,
+    ) -> Self {
+        let timezone = config.charging.timezone();
         Self {
             config,
+            policy,
+            source: DataSource::Online,
             start,
             records: vec![Record {
                 time: start,
-                time_str: format!("{}", start.with_timezone(&Pacific).time()),
+                time_str: format!("{}", start.with_timezone(&timezone).time()),
                 s10_soc: 0.1,
                 s30_soc: 0.3,
                 s50_soc: 0.5,
@@ -61,37 +138,72 @@ impl Simulator {
                 s30_emissions_used: 0,
                 s50_emissions_used: 0,
                 s70_emissions_used: 0,
+                s10_power_kw: 0.,
+                s30_power_kw: 0.,
+                s50_power_kw: 0.,
+                s70_power_kw: 0.,
+                s10_switches: 0,
+                s30_switches: 0,
+                s50_switches: 0,
+                s70_switches: 0,
+                price: 0.,
+                s10_cost: 0.,
+                s30_cost: 0.,
+                s50_cost: 0.,
+                s70_cost: 0.,
+                s10_avoided_kg: 0.,
+                s30_avoided_kg: 0.,
+                s50_avoided_kg: 0.,
+                s70_avoided_kg: 0.,
             }],
+            stabilizers: Default::default(),
+            meters: Default::default(),
+            baselines: Default::default(),
         }
     }
 
+    /// Read MOERs and forecasts from `source` instead of the SGIP API.
+    pub fn with_source(mut self, source: DataSource) -> Self {
+        self.source = source;
+        self
+    }
+
     pub fn take_records(self) -> Vec<Record> {
         self.records
     }
 
+    /// Run the simulation, reading data from the simulator's [`DataSource`].
     pub async fn run(&mut self) -> Result<(), Error> {
-        let mut sgip = SgipSignal::login(
-            &self.config.sgip_credentials.sgip_username,
-            &self.config.sgip_credentials.sgip_password,
-        )
-        .await?;
+        let mut source = self.source.open(&self.config).await?;
+        self.run_with(source.as_mut()).await
+    }
+
+    /// Run the simulation, reading data from a caller-supplied [`SignalSource`].
+    pub async fn run_with(&mut self, source: &mut dyn SignalSource) -> Result<(), Error> {
         let region = self.config.charging.region;
+        let timezone = self.config.charging.timezone();
         let end = self.start + Duration::hours(2 * self.config.charging.flex_charge_hours);
 
-        let forecasts = ForecastTable::crawl(&mut sgip, region, self.start..end).await?;
-        let history = History::new(
-            region,
-            sgip.historic_moers(region, self.start, Some(end + Duration::hours(1)))
-                .await?,
-        );
+        let forecasts = ForecastTable::crawl(source, region, self.start..end).await?;
+        let moers = source
+            .historic_moers(region, self.start, Some(end + Duration::hours(1)))
+            .await?;
+        let history = History::new(region, moers);
+
+        // Departure goals from the calendar apply as they would have live.
+        self.config.charging.one_off_goals = self.config.charging.calendar_goals(self.start)?;
 
         let step = Duration::minutes(5);
         let mut now = self.records.last().expect("records is nonempty").time;
         while now <= end {
             now = now + step;
 
-            let moer = history.at(now).unwrap();
-            let forecast = forecasts.at(now).unwrap();
+            let moer = history
+                .at(now)
+                .ok_or_else(|| anyhow!("no MOER data at {}", now))?;
+            let forecast = forecasts
+                .at(now)
+                .ok_or_else(|| anyhow!("no forecast generated before {}", now))?;
 
             let emissions = (moer.rate * 1000.) as u64;
 
@@ -100,54 +212,72 @@ impl Simulator {
             let mut s50_soc = self.records.last().unwrap().s50_soc;
             let mut s70_soc = self.records.last().unwrap().s70_soc;
 
-            let (s10_charge_now, s10_emissions_limit) = self
-                .config
-                .charging
-                .can_charge(now, s10_soc, &history, &moer, &forecast);
+            let charging = &self.config.charging;
+            let policy = &self.policy;
+
+            // The simulated vehicles are plugged in throughout, so the energy
+            // they've charged is their gain in state of charge.
+            let first = &self.records[0];
+            let [b10, b30, b50, b70] = &mut self.baselines;
+            let avoided_kg = |baseline: &mut Baseline, soc: f64, initial_soc: f64| {
+                let charged_kwh = (soc - initial_soc) * charging.capacity_kwh;
+                let charged = Charged::exact(now, charged_kwh);
+                baseline.update(charging, now, true, soc, moer.rate, charged);
+                baseline.avoided_kg()
+            };
+            let s10_avoided_kg = avoided_kg(b10, s10_soc, first.s10_soc);
+            let s30_avoided_kg = avoided_kg(b30, s30_soc, first.s30_soc);
+            let s50_avoided_kg = avoided_kg(b50, s50_soc, first.s50_soc);
+            let s70_avoided_kg = avoided_kg(b70, s70_soc, first.s70_soc);
 
-            let (s30_charge_now, s30_emissions_limit) = self
-                .config
-                .charging
-                .can_charge(now, s30_soc, &history, &moer, &forecast);
+            // Each vehicle's decision is held steady as the controller would.
+            let decide = |stabilizer: &mut Stabilizer, soc| {
+                let decision = policy.decide(charging, now, soc, &history, &moer, forecast);
+                let decision = stabilizer.stabilize(charging, now, soc, &moer, decision);
+                stabilizer.record(now, decision.power_kw);
+                (decision.power_kw, decision.emissions_limit)
+            };
 
-            let (s50_charge_now, s50_emissions_limit) = self
-                .config
-                .charging
-                .can_charge(now, s50_soc, &history, &moer, &forecast);
+            let [s10, s30, s50, s70] = &mut self.stabilizers;
 
-            let (s70_charge_now, s70_emissions_limit) = self
-                .config
-                .charging
-                .can_charge(now, s70_soc, &history, &moer, &forecast);
+            let (s10_power_kw, s10_emissions_limit) = decide(s10, s10_soc);
+
+            let (s30_power_kw, s30_emissions_limit) = decide(s30, s30_soc);
+
+            let (s50_power_kw, s50_emissions_limit) = decide(s50, s50_soc);
+
+            let (s70_power_kw, s70_emissions_limit) = decide(s70, s70_soc);
 
             tracing::info!(
-                now = ?now.with_timezone(&Pacific).time(),
+                now = ?now.with_timezone(&timezone).time(),
                 emissions,
                 s10_l = s10_emissions_limit,
                 s30_l = s30_emissions_limit,
                 s50_l = s50_emissions_limit,
                 s70_l = s70_emissions_limit,
                 s10_soc = ?F(s10_soc, 3),
-                //s10_charge_now,
                 s30_soc = ?F(s30_soc, 3),
-                //s30_charge_now,
                 s50_soc = ?F(s50_soc, 3),
-                //s50_charge_now,
                 s70_soc = ?F(s70_soc, 3),
-                //s70_charge_now,
             );
 
-            let delta = self.config.charging.charge_rate_kw * (step.num_minutes() as f64 / 60.0);
-            let delta_pct = delta / self.config.charging.capacity_kwh;
+            let hours = step.num_minutes() as f64 / 60.0;
+
+            let price = charging.price_at(now);
+            let [m10, m30, m50, m70] = &mut self.meters;
+            let s10_cost = m10.cost(charging, now, s10_power_kw, hours);
+            let s30_cost = m30.cost(charging, now, s30_power_kw, hours);
+            let s50_cost = m50.cost(charging, now, s50_power_kw, hours);
+            let s70_cost = m70.cost(charging, now, s70_power_kw, hours);
 
-            s10_soc += if s10_charge_now { delta_pct } else { 0. };
-            s30_soc += if s30_charge_now { delta_pct } else { 0. };
-            s50_soc += if s50_charge_now { delta_pct } else { 0. };
-            s70_soc += if s70_charge_now { delta_pct } else { 0. };
+            s10_soc += s10_power_kw * hours / self.config.charging.capacity_kwh;
+            s30_soc += s30_power_kw * hours / self.config.charging.capacity_kwh;
+            s50_soc += s50_power_kw * hours / self.config.charging.capacity_kwh;
+            s70_soc += s70_power_kw * hours / self.config.charging.capacity_kwh;
 
             self.records.push(Record {
                 time: now,
-                time_str: format!("{}", now.with_timezone(&Pacific).time()),
+                time_str: format!("{}", now.with_timezone(&timezone).time()),
                 s10_soc,
                 s30_soc,
                 s50_soc,
@@ -157,10 +287,27 @@ impl Simulator {
                 s30_emissions_limit,
                 s50_emissions_limit,
                 s70_emissions_limit,
-                s10_emissions_used: if s10_charge_now { emissions } else { 0 },
-                s30_emissions_used: if s30_charge_now { emissions } else { 0 },
-                s50_emissions_used: if s50_charge_now { emissions } else { 0 },
-                s70_emissions_used: if s70_charge_now { emissions } else { 0 },
+                s10_emissions_used: if s10_power_kw > 0. { emissions } else { 0 },
+                s30_emissions_used: if s30_power_kw > 0. { emissions } else { 0 },
+                s50_emissions_used: if s50_power_kw > 0. { emissions } else { 0 },
+                s70_emissions_used: if s70_power_kw > 0. { emissions } else { 0 },
+                s10_power_kw,
+                s30_power_kw,
+                s50_power_kw,
+                s70_power_kw,
+                s10_switches: s10.switches(),
+                s30_switches: s30.switches(),
+                s50_switches: s50.switches(),
+                s70_switches: s70.switches(),
+                price,
+                s10_cost,
+                s30_cost,
+                s50_cost,
+                s70_cost,
+                s10_avoided_kg,
+                s30_avoided_kg,
+                s50_avoided_kg,
+                s70_avoided_kg,
             });
         }
 
@@ -173,6 +320,15 @@ struct ForecastTable {
 }
 
 impl ForecastTable {
+    pub fn new(forecasts: impl IntoIterator<Item = Forecast>) -> Self {
+        Self {
+            data: forecasts
+                .into_iter()
+                .map(|forecast| (forecast.generated_at, forecast))
+                .collect(),
+        }
+    }
+
     pub fn at(&self, time: DateTime<Utc>) -> Option<&Forecast> {
         self.data
             .range(..=time)
@@ -181,22 +337,109 @@ impl ForecastTable {
     }
 
     pub async fn crawl(
-        sgip: &mut SgipSignal,
+        source: &mut dyn SignalSource,
         region: GridRegion,
         range: Range<DateTime<Utc>>,
     ) -> Result<Self, Error> {
-        let mut data = BTreeMap::default();
-
-        let mut start = range.start;
-        let step = Duration::days(1);
-        while start < range.end {
-            let end = std::cmp::min(start + step, range.end);
-            for forecast in sgip.historic_forecasts(region, start, end).await? {
-                data.insert(forecast.generated_at, forecast);
-            }
-            start = start + step;
+        let forecasts = source
+            .historic_forecasts(region, range.start, range.end)
+            .await?;
+        Ok(Self::new(forecasts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Timelike};
+    use chrono_tz::US::Pacific;
+
+    #[tokio::test]
+    async fn offline_simulation_is_deterministic() {
+        let dir = std::env::temp_dir().join(format!("sgip-simulator-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let moers_path = dir.join("moers.csv");
+        let forecasts_path = dir.join("forecasts.jsonl");
+
+        let mut config = Config::default();
+        config.charging.flex_charge_hours = 6;
+        let start = Pacific.ymd(2021, 3, 1).and_hms(0, 0, 0).with_timezone(&Utc);
+
+        // Emissions are clean between 02:00 and 04:00, and dirty otherwise.
+        let rate = |time: DateTime<Utc>| match time.with_timezone(&Pacific).hour() {
+            2..=3 => 0.2,
+            _ => 0.8,
+        };
+        let times = (0..(14 * 12))
+            .map(|i| start + Duration::minutes(5 * i))
+            .collect::<Vec<_>>();
+
+        let mut writer = csv::Writer::from_path(&moers_path).unwrap();
+        writer.write_record(["start", "rate"]).unwrap();
+        for time in &times {
+            writer
+                .write_record(&[time.to_rfc3339(), rate(*time).to_string()])
+                .unwrap();
+        }
+        writer.flush().unwrap();
+
+        // A forecast every hour, predicting the rest of the window.
+        let mut forecasts = String::new();
+        for generated_at in times.iter().step_by(12) {
+            let forecast = times
+                .iter()
+                .filter(|time| *time >= generated_at)
+                .map(|time| {
+                    serde_json::json!({
+                        "ba": config.charging.region.to_string(),
+                        "point_time": time,
+                        "value": rate(*time),
+                    })
+                })
+                .collect::<Vec<_>>();
+            forecasts += &serde_json::json!({
+                "generated_at": generated_at,
+                "forecast": forecast,
+            })
+            .to_string();
+            forecasts.push('\n');
         }
+        std::fs::write(&forecasts_path, forecasts).unwrap();
+
+        let source = DataSource::Files {
+            moers: moers_path,
+            forecasts: forecasts_path,
+        };
+        let simulate = || async {
+            let mut sim = Simulator::new(config.clone(), start).with_source(source.clone());
+            sim.run().await.unwrap();
+            sim.take_records()
+        };
+        let records = simulate().await;
+        let again = simulate().await;
+
+        // 12 hours of 5-minute steps, plus the initial record.
+        assert_eq!(records.len(), 12 * 12 + 2);
+        assert_eq!(
+            records.iter().map(|r| r.s10_soc).collect::<Vec<_>>(),
+            again.iter().map(|r| r.s10_soc).collect::<Vec<_>>(),
+        );
+        let last = records.last().unwrap();
+        assert!(last.s10_soc > 0.1);
+        assert!(records
+            .iter()
+            .any(|r| r.s50_power_kw > 0. && rate(r.time) < 0.5));
+        // Waiting for the clean hours avoids emissions, while the s10
+        // vehicle charges throughout, like the baseline.
+        assert!(last.s50_avoided_kg > 0.);
+        assert!(last.s10_avoided_kg.abs() < 1e-9);
+
+        // Running past the end of the data is an error, not a panic.
+        let mut config = config.clone();
+        config.charging.flex_charge_hours = 12;
+        let mut sim = Simulator::new(config, start).with_source(source.clone());
+        assert!(sim.run().await.is_err());
 
-        Ok(Self { data })
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
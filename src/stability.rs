@@ -0,0 +1,163 @@
+use chrono::{DateTime, Duration, Utc};
+use sgip_signal::Moer;
+
+use crate::{config, Decision};
+
+/// Holds charging decisions steady, so that noise in the emissions rate
+/// doesn't start and stop charging every interval.
+///
+/// Charging keeps going for at least `min_run_minutes` once started, and
+/// stays stopped for at least `min_rest_minutes` once stopped.  Past those
+/// times, decisions that depend on the emissions limit only switch once the
+/// current rate is outside the `emissions_hysteresis` band around the limit.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Stabilizer {
+    power_kw: f64,
+    /// When charging last started or stopped.
+    switched_at: Option<DateTime<Utc>>,
+    /// How many times charging has started or stopped.
+    switches: u32,
+}
+
+impl Stabilizer {
+    /// Adjust `decision` to keep doing what was last recorded, where the
+    /// config allows it.
+    pub fn stabilize(
+        &self,
+        config: &config::Charging,
+        now: DateTime<Utc>,
+        soc: f64,
+        current: &Moer,
+        mut decision: Decision,
+    ) -> Decision {
+        let charging = self.power_kw > 0.;
+        if decision.charge() == charging {
+            return decision;
+        }
+
+        // Never hold charging on when it isn't allowed, or hold it off when
+        // it must be continuous to meet a goal.
+        if charging && (!config.allowed_at(now) || soc >= config.charge_limit_at(now)) {
+            return decision;
+        }
+        if !charging && decision.urgency >= 1. {
+            return decision;
+        }
+
+        let min_dwell = Duration::minutes(if charging {
+            config.min_run_minutes
+        } else {
+            config.min_rest_minutes
+        });
+        let dwelling = self
+            .switched_at
+            .map(|switched_at| now - switched_at < min_dwell)
+            .unwrap_or(false);
+
+        let rate = config.objective(now, current.rate) * 1000.;
+        let limit = decision.emissions_limit as f64;
+        let in_band = decision.emissions_limit >= 0
+            && if charging {
+                rate <= limit + config.emissions_hysteresis
+            } else {
+                rate > limit - config.emissions_hysteresis
+            };
+
+        let reason = if dwelling {
+            "minimum run or rest time"
+        } else if in_band {
+            "emissions hysteresis"
+        } else {
+            return decision;
+        };
+        decision.power_kw = self.power_kw;
+        decision.explanation = format!(
+            "{} at {:.2} kW for {} (policy decided: {})",
+            if charging {
+                "still charging"
+            } else {
+                "still stopped"
+            },
+            self.power_kw,
+            reason,
+            decision.explanation
+        );
+        decision
+    }
+
+    /// Record the power the vehicle was commanded to charge at.
+    pub fn record(&mut self, now: DateTime<Utc>, power_kw: f64) {
+        if (power_kw > 0.) != (self.power_kw > 0.) {
+            self.switched_at = Some(now);
+            self.switches += 1;
+        }
+        self.power_kw = power_kw;
+    }
+
+    /// How many times charging has started or stopped.
+    pub fn switches(&self) -> u32 {
+        self.switches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveTime, TimeZone};
+    use sgip_signal::GridRegion;
+
+    #[test]
+    fn dwell_and_hysteresis() {
+        let config = config::Charging {
+            allowed_times: vec![(NaiveTime::from_hms(0, 0, 0), NaiveTime::from_hms(23, 0, 0))],
+            min_run_minutes: 15,
+            min_rest_minutes: 10,
+            emissions_hysteresis: 20.,
+            ..config::Charging::default()
+        };
+        let t0 = Utc.ymd(2021, 3, 1).and_hms(20, 0, 0);
+        let at = |minutes| t0 + Duration::minutes(minutes);
+        let moer = |rate| Moer {
+            region: GridRegion::CAISO_PGE,
+            rate,
+            start: t0,
+            duration: Duration::minutes(5),
+        };
+        let decision = |power_kw, emissions_limit| Decision {
+            power_kw,
+            emissions_limit,
+            urgency: 0.5,
+            explanation: String::new(),
+            factors: Vec::new(),
+        };
+
+        let mut stabilizer = Stabilizer::default();
+        let mut step = |minutes, rate, decided: Decision| {
+            let decided = stabilizer.stabilize(&config, at(minutes), 0.5, &moer(rate), decided);
+            stabilizer.record(at(minutes), decided.power_kw);
+            decided.power_kw
+        };
+
+        // Starting is held off until well below the limit.
+        assert_eq!(step(0, 0.490, decision(8., 500)), 0.);
+        assert_eq!(step(5, 0.470, decision(8., 500)), 8.);
+        // Stopping within the minimum run time is held off.
+        assert_eq!(step(10, 0.600, decision(0., 500)), 8.);
+        // Past it, stopping is held off until well above the limit.
+        assert_eq!(step(20, 0.510, decision(0., 500)), 8.);
+        assert_eq!(step(25, 0.530, decision(0., 500)), 0.);
+        // Restarting within the minimum rest time is held off, unless a goal
+        // needs continuous charging.
+        assert_eq!(step(30, 0.300, decision(8., 500)), 0.);
+        let urgent = Decision {
+            urgency: 1.2,
+            ..decision(8., 500)
+        };
+        assert_eq!(step(30, 0.300, urgent), 8.);
+
+        // Charging stops when it's no longer allowed, at 23:00 local time,
+        // whatever the emissions rate.
+        assert_eq!(step(11 * 60 + 5, 0.300, decision(0., -1)), 0.);
+        assert_eq!(stabilizer.switches(), 4);
+    }
+}
@@ -0,0 +1,297 @@
+use std::{ops::RangeInclusive, time::Duration};
+
+use anyhow::{anyhow, Error};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::config;
+
+/// How long to wait for a Modbus device to connect or respond.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+
+/// The addresses SunSpec devices may start their register map at.
+const BASE_ADDRESSES: [u16; 3] = [40000, 0, 50000];
+
+/// "SunS", marking the start of a SunSpec register map.
+const SUNSPEC_MARKER: [u16; 2] = [0x5375, 0x6e53];
+
+/// A minimal Modbus TCP client, which only reads holding registers.
+struct ModbusClient {
+    stream: TcpStream,
+    unit_id: u8,
+    transaction: u16,
+}
+
+impl ModbusClient {
+    async fn connect(device: &config::ModbusDevice) -> Result<Self, Error> {
+        let stream = tokio::time::timeout(TIMEOUT, TcpStream::connect(device.address)).await??;
+        Ok(Self {
+            stream,
+            unit_id: device.unit_id,
+            transaction: 0,
+        })
+    }
+
+    async fn read_holding_registers(
+        &mut self,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, Error> {
+        self.transaction = self.transaction.wrapping_add(1);
+        let mut request = Vec::with_capacity(12);
+        request.extend_from_slice(&self.transaction.to_be_bytes());
+        // The protocol identifier, then the length of the rest of the request.
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&6u16.to_be_bytes());
+        request.push(self.unit_id);
+        request.push(READ_HOLDING_REGISTERS);
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&count.to_be_bytes());
+
+        let pdu = tokio::time::timeout(TIMEOUT, self.exchange(&request)).await??;
+        match pdu.first() {
+            Some(&READ_HOLDING_REGISTERS) => {}
+            Some(&code) if code == READ_HOLDING_REGISTERS | 0x80 => {
+                return Err(anyhow!(
+                    "Modbus exception {:?} reading {} registers at {}",
+                    pdu.get(1),
+                    count,
+                    address
+                ));
+            }
+            _ => return Err(anyhow!("unexpected Modbus response {:?}", pdu)),
+        }
+        if pdu.len() != 2 + 2 * count as usize || pdu[1] as usize != 2 * count as usize {
+            return Err(anyhow!("Modbus response has the wrong length"));
+        }
+        Ok(pdu[2..]
+            .chunks(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect())
+    }
+
+    /// Send a request, returning the PDU of the response.
+    async fn exchange(&mut self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        self.stream.write_all(request).await?;
+
+        let mut header = [0u8; 7];
+        self.stream.read_exact(&mut header).await?;
+        let transaction = u16::from_be_bytes([header[0], header[1]]);
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if transaction != self.transaction || length < 2 {
+            return Err(anyhow!("unexpected Modbus response header {:?}", header));
+        }
+
+        // The length includes the unit identifier, already read.
+        let mut pdu = vec![0; length - 1];
+        self.stream.read_exact(&mut pdu).await?;
+        Ok(pdu)
+    }
+}
+
+/// A SunSpec device, with the addresses of the models it implements.
+struct SunSpec {
+    client: ModbusClient,
+    /// The ID and starting address of each model, in register map order.
+    models: Vec<(u16, u16)>,
+}
+
+impl SunSpec {
+    async fn connect(device: &config::ModbusDevice) -> Result<Self, Error> {
+        let mut client = ModbusClient::connect(device).await?;
+
+        let mut base = None;
+        for &address in &BASE_ADDRESSES {
+            // Devices reply with an exception for addresses outside their
+            // register map, so keep looking.
+            if let Ok(marker) = client.read_holding_registers(address, 2).await {
+                if marker == SUNSPEC_MARKER {
+                    base = Some(address);
+                    break;
+                }
+            }
+        }
+        let base = base.ok_or_else(|| anyhow!("{} is not a SunSpec device", device.address))?;
+
+        // Models follow the marker, each starting with its ID and length,
+        // until an end model with ID 0xffff.
+        let mut models = Vec::new();
+        let mut address = base + 2;
+        loop {
+            let header = client.read_holding_registers(address, 2).await?;
+            if header[0] == 0xffff {
+                break;
+            }
+            models.push((header[0], address));
+            address = address
+                .checked_add(2 + header[1])
+                .ok_or_else(|| anyhow!("SunSpec register map overflows"))?;
+        }
+        tracing::debug!(address = %device.address, ?models, "found SunSpec models");
+
+        Ok(Self { client, models })
+    }
+
+    /// Read a power register and its scale factor, at offsets from the start
+    /// of the first model in `ids`, in kW.
+    async fn power_kw(
+        &mut self,
+        ids: RangeInclusive<u16>,
+        offset: u16,
+        scale_offset: u16,
+    ) -> Result<f64, Error> {
+        let (id, address) = self
+            .models
+            .iter()
+            .copied()
+            .find(|(id, _)| ids.contains(id))
+            .ok_or_else(|| anyhow!("device has no SunSpec model in {:?}", ids))?;
+        let registers = self
+            .client
+            .read_holding_registers(address + offset, scale_offset - offset + 1)
+            .await?;
+        let value = registers[0] as i16;
+        let scale = *registers.last().unwrap() as i16;
+        // 0x8000 marks a value the device does not implement.
+        if value == i16::MIN || scale == i16::MIN {
+            return Err(anyhow!("SunSpec model {} does not report power", id));
+        }
+        Ok(value as f64 * 10f64.powi(scale as i32) / 1000.)
+    }
+
+    /// The AC power an inverter is producing, in kW, from an inverter model
+    /// (101 to 103).
+    async fn inverter_power_kw(&mut self) -> Result<f64, Error> {
+        self.power_kw(101..=103, 14, 15).await
+    }
+
+    /// The total power through a meter, in kW, from a meter model (201 to
+    /// 204).  This is positive when importing from the grid and negative
+    /// when exporting.
+    async fn meter_power_kw(&mut self) -> Result<f64, Error> {
+        self.power_kw(201..=204, 18, 22).await
+    }
+}
+
+/// A reading of the site's solar production and grid export.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SolarReading {
+    /// The power the inverter is producing, in kW.
+    pub production_kw: f64,
+    /// The power the site is exporting to the grid, in kW, or negative when
+    /// importing.
+    pub export_kw: f64,
+}
+
+/// The SunSpec devices monitoring a site's solar.
+pub(crate) struct SolarMeter {
+    inverter: SunSpec,
+    /// The grid meter, or `None` if it is a model on the inverter.
+    meter: Option<SunSpec>,
+}
+
+impl SolarMeter {
+    pub async fn connect(solar: &config::Solar) -> Result<Self, Error> {
+        let inverter = SunSpec::connect(&solar.inverter).await?;
+        let meter = match &solar.meter {
+            Some(meter) => Some(SunSpec::connect(meter).await?),
+            None => None,
+        };
+        Ok(Self { inverter, meter })
+    }
+
+    pub async fn read(&mut self) -> Result<SolarReading, Error> {
+        let production_kw = self.inverter.inverter_power_kw().await?;
+        let meter = self.meter.as_mut().unwrap_or(&mut self.inverter);
+        let export_kw = -meter.meter_power_kw().await?;
+        Ok(SolarReading {
+            production_kw,
+            export_kw,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    /// Serve a register map over Modbus TCP, replying with an exception to
+    /// reads outside it.
+    async fn stand_in(registers: HashMap<u16, u16>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 12];
+            while stream.read_exact(&mut request).await.is_ok() {
+                let address = u16::from_be_bytes([request[8], request[9]]);
+                let count = u16::from_be_bytes([request[10], request[11]]);
+                let values = (address..address + count)
+                    .map(|address| registers.get(&address).copied())
+                    .collect::<Option<Vec<_>>>();
+                let pdu = match values {
+                    Some(values) => {
+                        let mut pdu = vec![READ_HOLDING_REGISTERS, 2 * count as u8];
+                        for value in values {
+                            pdu.extend_from_slice(&value.to_be_bytes());
+                        }
+                        pdu
+                    }
+                    None => vec![READ_HOLDING_REGISTERS | 0x80, 0x02],
+                };
+                let mut response = request[..4].to_vec();
+                response.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+                response.push(request[6]);
+                response.extend(pdu);
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn read_inverter_and_meter() {
+        // A common model, an inverter model producing 4.52 kW, and a meter
+        // model exporting 3.1 kW, starting at 50000 rather than 40000.
+        let mut registers = HashMap::new();
+        let mut map = |start: u16, values: &[u16]| {
+            for (i, value) in values.iter().enumerate() {
+                registers.insert(start + i as u16, *value);
+            }
+        };
+        map(50000, &SUNSPEC_MARKER);
+        map(50002, &[1, 66]);
+        map(50004, &[0; 66]);
+        map(50070, &[103, 50]);
+        map(50072, &[0; 50]);
+        map(50084, &[4520, 0]);
+        map(50122, &[203, 105]);
+        map(50124, &[0; 105]);
+        map(50140, &[(-31i16) as u16, 0, 0, 0, 2]);
+        map(50229, &[0xffff, 0]);
+
+        let device = config::ModbusDevice {
+            address: stand_in(registers).await,
+            unit_id: 1,
+        };
+        let solar = config::Solar {
+            inverter: device,
+            meter: None,
+            min_export_kw: 1.,
+        };
+        let mut meter = SolarMeter::connect(&solar).await.unwrap();
+        assert_eq!(
+            meter.inverter.models,
+            vec![(1, 50002), (103, 50070), (203, 50122)]
+        );
+        let reading = meter.read().await.unwrap();
+        assert!((reading.production_kw - 4.52).abs() < 1e-9);
+        assert!((reading.export_kw - 3.1).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, Utc};
+
+use crate::{config, DurationExt};
+
+/// The tariff's prices at a particular time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Rate {
+    /// The energy price, in $ / kWh.
+    pub price: f64,
+    /// The demand charge on the peak power drawn during the current period,
+    /// in $ / kW.
+    pub demand_charge_per_kw: f64,
+    /// The length of the current period, in hours.
+    period_hours: f64,
+    /// The indices of the current season and period, if in a period.
+    period: Option<(usize, usize)>,
+}
+
+impl config::Tariff {
+    /// The index of the season covering `month`, from 1 to 12.
+    fn season_index(&self, month: u32) -> Option<usize> {
+        self.seasons.iter().position(|season| season.covers(month))
+    }
+
+    /// The prices at the local time `local`.  Overnight periods apply on the
+    /// weekdays they start on.
+    pub(crate) fn rate_at(&self, local: NaiveDateTime) -> Rate {
+        let season_index = match self.season_index(local.month()) {
+            Some(i) => i,
+            None => return Rate::default(),
+        };
+        let season = &self.seasons[season_index];
+
+        let date = local.date();
+        let time = local.time();
+        let on = |period: &config::TouPeriod, date: chrono::NaiveDate| {
+            period.weekdays.is_empty() || period.weekdays.contains(&date.weekday())
+        };
+        let current = season.periods.iter().enumerate().find(|(_, period)| {
+            if period.start < period.end {
+                (period.start..period.end).contains(&time) && on(period, date)
+            } else {
+                (time >= period.start && on(period, date))
+                    || (time < period.end && on(period, date.pred()))
+            }
+        });
+
+        match current {
+            Some((i, period)) => {
+                let mut length = period.end - period.start;
+                if length <= Duration::zero() {
+                    length = length + Duration::days(1);
+                }
+                Rate {
+                    price: period.price,
+                    demand_charge_per_kw: period.demand_charge_per_kw,
+                    period_hours: length.num_hours_f64(),
+                    period: Some((season_index, i)),
+                }
+            }
+            None => Rate {
+                price: season.price,
+                ..Rate::default()
+            },
+        }
+    }
+}
+
+impl config::Season {
+    /// Whether the season includes `month`, from 1 to 12.
+    pub(crate) fn covers(&self, month: u32) -> bool {
+        let (first, last) = self.months;
+        if first <= last {
+            (first..=last).contains(&month)
+        } else {
+            month >= first || month <= last
+        }
+    }
+}
+
+impl config::Charging {
+    /// The tariff's prices at `time`, or zero without a tariff.
+    pub(crate) fn tariff_rate_at(&self, time: DateTime<Utc>) -> Rate {
+        match &self.tariff {
+            Some(tariff) => tariff.rate_at(time.with_timezone(&self.timezone()).naive_local()),
+            None => Rate::default(),
+        }
+    }
+
+    /// The energy price at `time`, in $ / kWh, or zero without a tariff.
+    pub fn price_at(&self, time: DateTime<Utc>) -> f64 {
+        self.tariff_rate_at(time).price
+    }
+
+    /// The quantity that policies minimize by choosing when to charge, given
+    /// the emissions `rate` at `time` in kg CO2 / kWh.  Without a tariff this
+    /// is just the emissions rate.
+    ///
+    /// Demand charges are counted as if spread over a single occurrence of
+    /// their period, which is their cost per kWh when charging through it.
+    pub(crate) fn objective(&self, time: DateTime<Utc>, rate: f64) -> f64 {
+        let cost_weight = match &self.tariff {
+            Some(tariff) => tariff.cost_weight,
+            None => return rate,
+        };
+        let tariff_rate = self.tariff_rate_at(time);
+        let mut price = tariff_rate.price;
+        if tariff_rate.demand_charge_per_kw > 0. {
+            price += tariff_rate.demand_charge_per_kw / tariff_rate.period_hours;
+        }
+        (1. - cost_weight) * rate + cost_weight * price
+    }
+}
+
+/// Tracks the peak power drawn during each demand-charged period of each
+/// month, to work out the cost of charging.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DemandMeter {
+    /// The peak power, in kW, by local year, month, season, and period.
+    peaks: HashMap<(i32, u32, usize, usize), f64>,
+}
+
+impl DemandMeter {
+    /// The cost, in $, of charging at `power_kw` for `hours` from `time`:
+    /// the energy cost, plus the demand charge on any increase in the month's
+    /// peak power.
+    pub fn cost(
+        &mut self,
+        config: &config::Charging,
+        time: DateTime<Utc>,
+        power_kw: f64,
+        hours: f64,
+    ) -> f64 {
+        let rate = config.tariff_rate_at(time);
+        let mut cost = rate.price * power_kw * hours;
+        if let Some((season, period)) = rate.period {
+            let local = time.with_timezone(&config.timezone());
+            let peak = self
+                .peaks
+                .entry((local.year(), local.month(), season, period))
+                .or_insert(0.);
+            if power_kw > *peak {
+                cost += rate.demand_charge_per_kw * (power_kw - *peak);
+                *peak = power_kw;
+            }
+        }
+        cost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveTime, TimeZone, Weekday};
+    use chrono_tz::US::Pacific;
+
+    #[test]
+    fn tou_prices_and_demand_charges() {
+        let time = |h| NaiveTime::from_hms(h, 0, 0);
+        let tariff = config::Tariff {
+            cost_weight: 0.5,
+            seasons: vec![
+                config::Season {
+                    months: (6, 9),
+                    price: 0.3,
+                    periods: vec![config::TouPeriod {
+                        start: time(16),
+                        end: time(21),
+                        weekdays: vec![
+                            Weekday::Mon,
+                            Weekday::Tue,
+                            Weekday::Wed,
+                            Weekday::Thu,
+                            Weekday::Fri,
+                        ],
+                        price: 0.5,
+                        demand_charge_per_kw: 10.,
+                    }],
+                },
+                config::Season {
+                    months: (10, 5),
+                    price: 0.25,
+                    periods: vec![config::TouPeriod {
+                        start: time(23),
+                        end: time(7),
+                        weekdays: Vec::new(),
+                        price: 0.1,
+                        demand_charge_per_kw: 0.,
+                    }],
+                },
+            ],
+        };
+        let tariff = crate::Validate::validate(tariff).unwrap();
+
+        // Seasons may not overlap, and prices may not be negative.
+        let mut overlapping = tariff.clone();
+        overlapping.seasons[0].months = (5, 9);
+        assert!(crate::Validate::validate(overlapping).is_err());
+        let mut negative = tariff.clone();
+        negative.seasons[1].periods[0].price = -0.05;
+        assert!(crate::Validate::validate(negative).is_err());
+        let at = |m, d, h| NaiveDate::from_ymd(2021, m, d).and_hms(h, 0, 0);
+
+        // Peak on a summer weekday, but not at the weekend.
+        assert_eq!(tariff.rate_at(at(7, 2, 17)).price, 0.5);
+        assert_eq!(tariff.rate_at(at(7, 3, 17)).price, 0.3);
+        // The winter season runs over the new year, and its off-peak period
+        // runs overnight.
+        assert_eq!(tariff.rate_at(at(1, 4, 6)).price, 0.1);
+        assert_eq!(tariff.rate_at(at(1, 4, 7)).price, 0.25);
+        assert_eq!(tariff.rate_at(at(12, 31, 23)).price, 0.1);
+
+        let config = config::Charging {
+            tariff: Some(tariff),
+            ..config::Charging::default()
+        };
+        let peak = Pacific
+            .ymd(2021, 7, 2)
+            .and_hms(17, 0, 0)
+            .with_timezone(&Utc);
+        // Half of the emissions rate, and half of the price with the demand
+        // charge spread over the 5-hour period.
+        assert!((config.objective(peak, 0.4) - (0.2 + 0.5 * (0.5 + 2.))).abs() < 1e-9);
+
+        // Demand charges only apply to increases in each month's peak.
+        let mut meter = DemandMeter::default();
+        let cost = meter.cost(&config, peak, 6., 0.5);
+        assert!((cost - (0.5 * 3. + 60.)).abs() < 1e-9);
+        let cost = meter.cost(&config, peak + Duration::days(3), 4., 0.5);
+        assert!((cost - 0.5 * 2.).abs() < 1e-9);
+        let cost = meter.cost(&config, peak + Duration::days(31), 4., 0.5);
+        assert!((cost - (0.5 * 2. + 40.)).abs() < 1e-9);
+    }
+}
@@ -1,9 +1,11 @@
 mod auth;
 mod charge;
+mod energy;
 mod vehicle;
 
 static BASE_URL: &str = "https://owner-api.teslamotors.com/";
 
 pub use auth::AccessToken;
 pub use charge::ChargeState;
+pub use energy::{EnergySite, ProductData, SiteInfo, SiteStatus};
 pub use vehicle::{Vehicle, VehicleData};
@@ -46,3 +46,59 @@ pub struct ChargeState {
     pub usable_battery_level: u32,
     //pub user_charge_enable_request: Null,
 }
+
+#[cfg(test)]
+impl ChargeState {
+    /// A charge state with the given battery level, energy added in the
+    /// current session, and charging state, for tests.
+    pub(crate) fn example(
+        battery_level: u32,
+        charge_energy_added: f32,
+        charging_state: &str,
+    ) -> Self {
+        serde_json::from_value(serde_json::json!({
+            "battery_heater_on": false,
+            "battery_level": battery_level,
+            "battery_range": 100.0,
+            "charge_current_request": 32,
+            "charge_current_request_max": 32,
+            "charge_enable_request": true,
+            "charge_energy_added": charge_energy_added,
+            "charge_limit_soc": 90,
+            "charge_limit_soc_max": 100,
+            "charge_limit_soc_min": 50,
+            "charge_limit_soc_std": 90,
+            "charge_miles_added_ideal": 0.0,
+            "charge_miles_added_rated": 0.0,
+            "charge_port_cold_weather_mode": false,
+            "charge_port_door_open": true,
+            "charge_port_latch": "Engaged",
+            "charge_rate": 0.0,
+            "charge_to_max_range": false,
+            "charger_actual_current": 0,
+            "charger_phases": null,
+            "charger_pilot_current": 32,
+            "charger_power": 0,
+            "charger_voltage": 0,
+            "charging_state": charging_state,
+            "conn_charge_cable": "SAE",
+            "est_battery_range": 100.0,
+            "fast_charger_brand": "<invalid>",
+            "fast_charger_present": false,
+            "fast_charger_type": "<invalid>",
+            "ideal_battery_range": 100.0,
+            "managed_charging_active": false,
+            "managed_charging_user_canceled": false,
+            "max_range_charge_counter": 0,
+            "minutes_to_full_charge": 0,
+            "not_enough_power_to_heat": null,
+            "scheduled_charging_pending": false,
+            "scheduled_charging_start_time": null,
+            "time_to_full_charge": 0.0,
+            "timestamp": 0,
+            "trip_charging": false,
+            "usable_battery_level": battery_level,
+        }))
+        .unwrap()
+    }
+}
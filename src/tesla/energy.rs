@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Error};
+use serde::Deserialize;
+
+use super::{AccessToken, BASE_URL};
+
+#[derive(Deserialize, Debug)]
+pub struct ProductData {
+    pub energy_site_id: Option<u64>,
+    pub resource_type: Option<String>,
+    pub site_name: Option<String>,
+}
+
+/// The live state of an energy site.  Powers are in W, and positive when
+/// the battery discharges, the site imports from the grid, or solar produces.
+#[derive(Deserialize, Debug)]
+pub struct SiteStatus {
+    pub percentage_charged: f64,
+    pub energy_left: f64,
+    pub total_pack_energy: f64,
+    pub battery_power: f64,
+    #[serde(default)]
+    pub solar_power: f64,
+    #[serde(default)]
+    pub grid_power: f64,
+    #[serde(default)]
+    pub load_power: f64,
+    #[serde(default)]
+    pub grid_status: Option<String>,
+}
+
+/// The configured behaviour of an energy site.
+#[derive(Deserialize, Debug)]
+pub struct SiteInfo {
+    pub site_name: Option<String>,
+    pub default_real_mode: String,
+    pub backup_reserve_percent: f64,
+}
+
+/// An energy site with Powerwall storage.
+#[derive(Debug, Clone)]
+pub struct EnergySite {
+    pub id: u64,
+    pub site_name: String,
+    // this client has the token preconfigured
+    client: reqwest::Client,
+}
+
+impl AccessToken {
+    /// The energy sites on the account that have batteries.
+    #[tracing::instrument(skip(self))]
+    pub async fn energy_sites(&self, user_agent: &str) -> Result<Vec<EnergySite>, Error> {
+        let client = self.build_client(user_agent);
+
+        #[derive(Deserialize)]
+        struct Response {
+            response: Vec<ProductData>,
+        }
+
+        let products = client
+            .get(format!("{}/api/1/products", BASE_URL))
+            .send()
+            .await?
+            .json::<Response>()
+            .await?
+            .response;
+
+        Ok(products
+            .into_iter()
+            .filter(|product| product.resource_type.as_deref() == Some("battery"))
+            .filter_map(|product| {
+                Some(EnergySite {
+                    id: product.energy_site_id?,
+                    site_name: product.site_name.unwrap_or_default(),
+                    client: client.clone(),
+                })
+            })
+            .collect())
+    }
+}
+
+impl EnergySite {
+    /// A name for the site in logs: its site name, or its ID if it has none.
+    pub fn name(&self) -> String {
+        if self.site_name.is_empty() {
+            self.id.to_string()
+        } else {
+            self.site_name.clone()
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn live_status(&self) -> Result<SiteStatus, Error> {
+        #[derive(Deserialize)]
+        struct Response {
+            response: SiteStatus,
+        }
+
+        Ok(self
+            .client
+            .get(format!(
+                "{}/api/1/energy_sites/{}/live_status",
+                BASE_URL, self.id
+            ))
+            .send()
+            .await?
+            .json::<Response>()
+            .await?
+            .response)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn site_info(&self) -> Result<SiteInfo, Error> {
+        #[derive(Deserialize)]
+        struct Response {
+            response: SiteInfo,
+        }
+
+        Ok(self
+            .client
+            .get(format!(
+                "{}/api/1/energy_sites/{}/site_info",
+                BASE_URL, self.id
+            ))
+            .send()
+            .await?
+            .json::<Response>()
+            .await?
+            .response)
+    }
+
+    /// Set the operating mode, such as `self_consumption`, `autonomous`, or
+    /// `backup`.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_operation_mode(&self, mode: &str) -> Result<(), Error> {
+        self.command(
+            "operation",
+            serde_json::json!({ "default_real_mode": mode }),
+        )
+        .await
+    }
+
+    /// Set the percentage of the battery reserved for backup.  The battery
+    /// charges from the grid when below the reserve, and does not discharge
+    /// below it.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_backup_reserve(&self, percent: u32) -> Result<(), Error> {
+        self.command(
+            "backup",
+            serde_json::json!({ "backup_reserve_percent": percent }),
+        )
+        .await
+    }
+
+    async fn command(&self, command: &str, body: serde_json::Value) -> Result<(), Error> {
+        #[derive(Deserialize)]
+        struct Response {
+            response: CommandResponse,
+        }
+        #[derive(Deserialize)]
+        struct CommandResponse {
+            code: u32,
+            message: String,
+        }
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/api/1/energy_sites/{}/{}",
+                BASE_URL, self.id, command
+            ))
+            .json(&body)
+            .send()
+            .await?
+            .json::<Response>()
+            .await?
+            .response;
+
+        if response.code == 201 || response.code == 200 {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "request failed, code={}, message={}",
+                response.code,
+                response.message
+            ))
+        }
+    }
+}
@@ -22,7 +22,7 @@ pub struct VehicleData {
 }
 
 impl VehicleData {
-    fn is_online(&self) -> bool {
+    pub fn is_online(&self) -> bool {
         self.state == "online"
     }
 }
@@ -31,6 +31,8 @@ impl VehicleData {
 pub struct Vehicle {
     pub id: u64,
     pub vehicle_id: u64,
+    pub vin: String,
+    pub display_name: String,
     // this client has the token preconfigured
     // no handling of refreshes, so it will stop working after 45 days
     client: reqwest::Client,
@@ -56,16 +58,41 @@ impl AccessToken {
 
         Ok(vehicles
             .into_iter()
-            .map(|VehicleData { id, vehicle_id, .. }| Vehicle {
-                vehicle_id,
-                id,
-                client: client.clone(),
-            })
+            .map(
+                |VehicleData {
+                     id,
+                     vehicle_id,
+                     vin,
+                     display_name,
+                     ..
+                 }| Vehicle {
+                    vehicle_id,
+                    id,
+                    vin,
+                    display_name,
+                    client: client.clone(),
+                },
+            )
             .collect())
     }
 }
 
 impl Vehicle {
+    /// A name for the vehicle in logs and metrics: its display name, or its
+    /// VIN if it has none.
+    pub fn name(&self) -> &str {
+        if self.display_name.is_empty() {
+            &self.vin
+        } else {
+            &self.display_name
+        }
+    }
+
+    /// Whether `name` is the vehicle's VIN or display name.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.vin == name || self.display_name == name
+    }
+
     /// Wake the vehicle from sleep, returning only when the vehicle reports that it is online.
     #[tracing::instrument(skip(self))]
     pub async fn wake(&self) -> Result<(), Error> {
@@ -204,4 +231,38 @@ impl Vehicle {
             Err(anyhow!("request failed, reason={}", response.reason))
         }
     }
+
+    /// Set the charging current, in amps.  The vehicle limits this to
+    /// [`ChargeState::charge_current_request_max`].
+    #[tracing::instrument(skip(self))]
+    pub async fn set_charging_amps(&self, charging_amps: u32) -> Result<(), Error> {
+        #[derive(Deserialize)]
+        struct Response {
+            response: ChargeResponse,
+        }
+        #[derive(Deserialize)]
+        struct ChargeResponse {
+            result: bool,
+            reason: String,
+        }
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/api/1/vehicles/{}/command/set_charging_amps",
+                BASE_URL, self.id
+            ))
+            .json(&serde_json::json!({ "charging_amps": charging_amps }))
+            .send()
+            .await?
+            .json::<Response>()
+            .await?
+            .response;
+
+        if response.result {
+            Ok(())
+        } else {
+            Err(anyhow!("request failed, reason={}", response.reason))
+        }
+    }
 }
@@ -0,0 +1,199 @@
+use chrono::{DateTime, Utc};
+
+use crate::{tesla::ChargeState, DurationExt};
+
+/// Growth in state of charge uncertainty per hour, from vampire drain and
+/// other unmodeled use while the vehicle is asleep.
+const IDLE_DRIFT_PER_HOUR: f64 = 0.001;
+/// Relative error of dead-reckoned charging energy.
+const CHARGE_ENERGY_ERROR: f64 = 0.1;
+/// Uncertainty of an observed state of charge, which is reported in whole percent.
+const OBSERVATION_UNCERTAINTY: f64 = 0.005;
+
+/// A model of the vehicle's charging state, dead-reckoned from the commanded
+/// charging power between observations, so that the controller only needs to
+/// wake the vehicle when its decision changes or the estimate becomes too
+/// uncertain.
+#[derive(Clone, Debug)]
+pub struct VehicleModel {
+    capacity_kwh: f64,
+    /// The estimated state of charge, as of `updated_at`.
+    soc: f64,
+    /// The estimated error bound on `soc`.
+    uncertainty: f64,
+    updated_at: Option<DateTime<Utc>>,
+    /// The charging power last commanded, in kW.
+    power_kw: f64,
+    /// The ratio of energy actually added to energy commanded.
+    efficiency: f64,
+    plugged_in: bool,
+    /// The `charge_energy_added` reported at the last observation, together
+    /// with the energy commanded since then.
+    energy_added: Option<f64>,
+    commanded_kwh: f64,
+    /// The energy charged since the model was created, in kWh, estimated
+    /// from the commanded power.
+    estimated_kwh: f64,
+    /// The energy the vehicle has reported adding since the model was
+    /// created, in kWh, as of `observed_at`.
+    observed_kwh: f64,
+    observed_at: Option<DateTime<Utc>>,
+}
+
+impl VehicleModel {
+    pub fn new(capacity_kwh: f64) -> Self {
+        Self {
+            capacity_kwh,
+            soc: 0.,
+            uncertainty: f64::INFINITY,
+            updated_at: None,
+            power_kw: 0.,
+            efficiency: 1.,
+            plugged_in: false,
+            energy_added: None,
+            commanded_kwh: 0.,
+            estimated_kwh: 0.,
+            observed_kwh: 0.,
+            observed_at: None,
+        }
+    }
+
+    /// The estimated state of charge.
+    pub fn soc(&self) -> f64 {
+        self.soc
+    }
+
+    /// The estimated error bound on the state of charge.
+    pub fn uncertainty(&self) -> f64 {
+        self.uncertainty
+    }
+
+    /// The charging power last commanded, in kW.
+    pub fn power_kw(&self) -> f64 {
+        self.power_kw
+    }
+
+    /// The energy charged since the model was created, in kWh, estimated
+    /// from the commanded power alone.  Commanded charging stops counting
+    /// once the battery is full.
+    pub fn estimated_kwh(&self) -> f64 {
+        self.estimated_kwh
+    }
+
+    /// The energy the vehicle has reported adding since the model was
+    /// created, in kWh, with when it was last observed.
+    pub fn observed_energy(&self) -> Option<(DateTime<Utc>, f64)> {
+        self.observed_at.map(|at| (at, self.observed_kwh))
+    }
+
+    /// Whether the vehicle was plugged in when last observed.
+    pub fn plugged_in(&self) -> bool {
+        self.plugged_in
+    }
+
+    /// Returns whether the estimate is too uncertain to act on without
+    /// observing the vehicle.
+    pub fn needs_observation(&self, max_uncertainty: f64) -> bool {
+        self.uncertainty > max_uncertainty
+    }
+
+    /// Dead-reckon the state of charge forward to `now`.
+    pub fn advance(&mut self, now: DateTime<Utc>) {
+        let updated_at = match self.updated_at {
+            Some(updated_at) if updated_at < now => updated_at,
+            _ => return,
+        };
+        let hours = (now - updated_at).num_hours_f64();
+        let commanded_kwh = self.power_kw * hours;
+
+        self.estimated_kwh +=
+            commanded_kwh.min((1. - self.soc).max(0.) * self.capacity_kwh / self.efficiency);
+        self.soc = (self.soc + commanded_kwh * self.efficiency / self.capacity_kwh).min(1.);
+        self.uncertainty +=
+            hours * IDLE_DRIFT_PER_HOUR + commanded_kwh * CHARGE_ENERGY_ERROR / self.capacity_kwh;
+        self.commanded_kwh += commanded_kwh;
+        self.updated_at = Some(now);
+    }
+
+    /// Record that the vehicle was commanded to charge at `power_kw`.
+    pub fn command(&mut self, now: DateTime<Utc>, power_kw: f64) {
+        self.advance(now);
+        self.power_kw = power_kw;
+    }
+
+    /// Reset the model from an observed charge state.
+    pub fn observe(&mut self, now: DateTime<Utc>, charge_state: &ChargeState) {
+        self.advance(now);
+
+        // Calibrate the charging efficiency against the energy the vehicle
+        // reports adding during the current session.  Implausible ratios mean
+        // the vehicle stopped charging (e.g. it was unplugged or reached its
+        // own limit), so don't learn from them.
+        let energy_added = charge_state.charge_energy_added as f64;
+        if let Some(previous) = self.energy_added {
+            // The vehicle restarts its count in each charging session.
+            self.observed_kwh += if energy_added >= previous {
+                energy_added - previous
+            } else {
+                energy_added
+            };
+            if self.commanded_kwh > 0.5 && energy_added >= previous {
+                let ratio = (energy_added - previous) / self.commanded_kwh;
+                if (0.5..1.5).contains(&ratio) {
+                    self.efficiency = (self.efficiency + ratio) / 2.;
+                }
+            }
+        }
+
+        self.soc = charge_state.battery_level as f64 / 100.;
+        self.uncertainty = OBSERVATION_UNCERTAINTY;
+        self.updated_at = Some(now);
+        self.plugged_in = charge_state.charging_state != "Disconnected";
+        if !self.plugged_in {
+            self.power_kw = 0.;
+        }
+        self.energy_added = Some(energy_added);
+        self.commanded_kwh = 0.;
+        self.observed_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn charge_state(battery_level: u32, charge_energy_added: f32) -> ChargeState {
+        ChargeState::example(battery_level, charge_energy_added, "Stopped")
+    }
+
+    #[test]
+    fn dead_reckoning_and_calibration() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let mut model = VehicleModel::new(50.);
+        assert!(model.needs_observation(0.03));
+
+        model.observe(start, &charge_state(40, 0.));
+        assert!(!model.needs_observation(0.03));
+        assert!(model.plugged_in());
+
+        // One hour at 5 kW adds 10% of a 50 kWh battery.
+        model.command(start, 5.);
+        model.advance(start + Duration::hours(1));
+        assert!((model.soc() - 0.5).abs() < 1e-9);
+        assert!(model.uncertainty() > OBSERVATION_UNCERTAINTY);
+        assert!((model.estimated_kwh() - 5.).abs() < 1e-9);
+
+        // The vehicle only added 4 kWh, so the efficiency estimate drops,
+        // and the reported energy is kept alongside the estimate.
+        model.observe(start + Duration::hours(1), &charge_state(48, 4.));
+        assert_eq!(model.soc(), 0.48);
+        assert!((model.estimated_kwh() - 5.).abs() < 1e-9);
+        assert_eq!(
+            model.observed_energy(),
+            Some((start + Duration::hours(1), 4.))
+        );
+        model.advance(start + Duration::hours(2));
+        assert!((model.soc() - (0.48 + 5. * 0.9 / 50.)).abs() < 1e-9);
+    }
+}
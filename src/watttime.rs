@@ -0,0 +1,387 @@
+//! A client for the WattTime v3 API, for use as an emissions signal outside
+//! of the regions covered by SGIP.
+
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Deserialize};
+use sgip_signal::{Forecast, GridRegion, Moer};
+
+use crate::SignalSource;
+
+static BASE_URL: &str = "https://api.watttime.org";
+
+/// Tokens are valid for 30 minutes, so renew them a little early.
+const TOKEN_LIFETIME_MINUTES: i64 = 25;
+const LBS_PER_KG: f64 = 2.204_622_62;
+
+/// The WattTime region covering each SGIP region.
+pub fn default_region(region: GridRegion) -> &'static str {
+    match region {
+        GridRegion::CAISO_PGE => "CAISO_NORTH",
+        GridRegion::CAISO_SCE => "CAISO_SANBERNARDINO",
+        GridRegion::CAISO_SDGE => "CAISO_ESCONDIDO",
+        GridRegion::LADWP => "LDWP",
+        GridRegion::BANC_SMUD | GridRegion::BANC_P2 => "BANC",
+        GridRegion::IID => "IID",
+        GridRegion::PACW => "PACW",
+        GridRegion::NVENERGY => "NEVP",
+        GridRegion::TID => "TIDC",
+        GridRegion::WALC => "WALC",
+    }
+}
+
+/// Convert a WattTime MOER, in lbs CO2 / MWh, to kg CO2 / kWh.
+fn kg_per_kwh(lbs_per_mwh: f64) -> f64 {
+    lbs_per_mwh / LBS_PER_KG / 1000.
+}
+
+#[derive(Deserialize)]
+struct DataPoint {
+    point_time: DateTime<Utc>,
+    value: f64,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    units: String,
+    #[serde(default)]
+    data_point_period_seconds: Option<i64>,
+    #[serde(default)]
+    generated_at: Option<DateTime<Utc>>,
+}
+
+impl Meta {
+    fn check_units(&self) -> Result<(), Error> {
+        if self.units != "lbs_co2_per_mwh" {
+            return Err(anyhow!("unexpected WattTime units {}", self.units));
+        }
+        Ok(())
+    }
+
+    fn period(&self) -> Duration {
+        Duration::seconds(self.data_point_period_seconds.unwrap_or(300))
+    }
+}
+
+#[derive(Deserialize)]
+struct Response<T> {
+    data: Vec<T>,
+    meta: Meta,
+}
+
+#[derive(Deserialize)]
+struct GeneratedForecast {
+    generated_at: DateTime<Utc>,
+    forecast: Vec<DataPoint>,
+}
+
+/// A logged-in WattTime API client.
+///
+/// WattTime data is labeled with the [`GridRegion`] it was requested for.
+/// Outside of California, that region only serves as a label, and the
+/// WattTime region to query must be set with [`WattTime::with_region`].
+#[derive(Clone, Debug)]
+pub struct WattTime {
+    client: reqwest::Client,
+    base_url: String,
+    username: String,
+    password: String,
+    token: String,
+    token_expires_at: DateTime<Utc>,
+    region: Option<String>,
+}
+
+impl WattTime {
+    pub async fn login(username: &str, password: &str) -> Result<Self, Error> {
+        Self::login_at(BASE_URL, username, password).await
+    }
+
+    /// Log in to a WattTime API server at `base_url`.
+    pub async fn login_at(base_url: &str, username: &str, password: &str) -> Result<Self, Error> {
+        let mut watttime = Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            token: String::new(),
+            token_expires_at: Utc::now(),
+            region: None,
+        };
+        watttime.refresh_token().await?;
+        Ok(watttime)
+    }
+
+    /// Query `region` rather than the WattTime region covering each
+    /// [`GridRegion`].
+    pub fn with_region(mut self, region: Option<String>) -> Self {
+        self.region = region;
+        self
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn refresh_token(&mut self) -> Result<(), Error> {
+        #[derive(Deserialize)]
+        struct Login {
+            token: String,
+        }
+
+        let now = Utc::now();
+        self.token = self
+            .client
+            .get(format!("{}/login", self.base_url))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Login>()
+            .await?
+            .token;
+        self.token_expires_at = now + Duration::minutes(TOKEN_LIFETIME_MINUTES);
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &mut self,
+        path: &str,
+        region: GridRegion,
+        query: &[(&str, String)],
+    ) -> Result<T, Error> {
+        if Utc::now() >= self.token_expires_at {
+            self.refresh_token().await?;
+        }
+        let region = self
+            .region
+            .clone()
+            .unwrap_or_else(|| default_region(region).to_string());
+
+        Ok(self
+            .client
+            .get(format!("{}{}", self.base_url, path))
+            .bearer_auth(&self.token)
+            .query(&[("region", region), ("signal_type", "co2_moer".to_string())])
+            .query(query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl SignalSource for WattTime {
+    /// The most recent historical MOER, since WattTime does not serve the
+    /// current MOER directly.
+    async fn moer(&mut self, region: GridRegion) -> Result<Moer, Error> {
+        let now = Utc::now();
+        self.historic_moers(region, now - Duration::hours(1), Some(now))
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("no WattTime MOERs in the last hour"))
+    }
+
+    async fn forecast(&mut self, region: GridRegion) -> Result<Forecast, Error> {
+        let response: Response<DataPoint> = self
+            .get(
+                "/v3/forecast",
+                region,
+                &[("horizon_hours", "24".to_string())],
+            )
+            .await?;
+        response.meta.check_units()?;
+
+        Ok(Forecast {
+            region,
+            generated_at: response
+                .meta
+                .generated_at
+                .ok_or_else(|| anyhow!("WattTime forecast is missing generated_at"))?,
+            data: response
+                .data
+                .into_iter()
+                .map(|point| (point.point_time, kg_per_kwh(point.value)))
+                .collect(),
+        })
+    }
+
+    async fn historic_moers(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Moer>, Error> {
+        let end = end.unwrap_or_else(Utc::now);
+        let response: Response<DataPoint> = self
+            .get(
+                "/v3/historical",
+                region,
+                &[("start", start.to_rfc3339()), ("end", end.to_rfc3339())],
+            )
+            .await?;
+        response.meta.check_units()?;
+
+        let duration = response.meta.period();
+        let mut moers = response
+            .data
+            .into_iter()
+            .map(|point| Moer {
+                region,
+                rate: kg_per_kwh(point.value),
+                start: point.point_time,
+                duration,
+            })
+            .collect::<Vec<_>>();
+        moers.sort_by_key(|moer| moer.start);
+        Ok(moers)
+    }
+
+    async fn historic_forecasts(
+        &mut self,
+        region: GridRegion,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Forecast>, Error> {
+        // Historical forecast queries are limited to one day.
+        let mut forecasts = Vec::new();
+        let mut chunk_start = start;
+        while chunk_start < end {
+            let chunk_end = std::cmp::min(chunk_start + Duration::days(1), end);
+            let response: Response<GeneratedForecast> = self
+                .get(
+                    "/v3/forecast/historical",
+                    region,
+                    &[
+                        ("start", chunk_start.to_rfc3339()),
+                        ("end", chunk_end.to_rfc3339()),
+                    ],
+                )
+                .await?;
+            response.meta.check_units()?;
+
+            forecasts.extend(response.data.into_iter().map(|generated| {
+                Forecast {
+                    region,
+                    generated_at: generated.generated_at,
+                    data: generated
+                        .forecast
+                        .into_iter()
+                        .map(|point| (point.point_time, kg_per_kwh(point.value)))
+                        .collect(),
+                }
+            }));
+            chunk_start = chunk_end;
+        }
+        Ok(forecasts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    async fn stub_server() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/login"))
+            .and(header("Authorization", "Basic dXNlcjpwYXNz"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "token": "t0ken" })))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn meta() -> serde_json::Value {
+        json!({
+            "data_point_period_seconds": 300,
+            "signal_type": "co2_moer",
+            "units": "lbs_co2_per_mwh",
+            "generated_at": "2021-03-01T00:00:00Z",
+        })
+    }
+
+    #[tokio::test]
+    async fn historic_moers_and_forecast() {
+        let server = stub_server().await;
+        Mock::given(method("GET"))
+            .and(path("/v3/historical"))
+            .and(query_param("region", "CAISO_NORTH"))
+            .and(header("Authorization", "Bearer t0ken"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [
+                    { "point_time": "2021-03-01T00:05:00Z", "value": 1000.0 },
+                    { "point_time": "2021-03-01T00:00:00Z", "value": 500.0 },
+                ],
+                "meta": meta(),
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v3/forecast"))
+            .and(query_param("region", "PJM_DC"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{ "point_time": "2021-03-01T00:05:00Z", "value": 1000.0 }],
+                "meta": meta(),
+            })))
+            .mount(&server)
+            .await;
+
+        let region = GridRegion::CAISO_PGE;
+        let t0 = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let mut watttime = WattTime::login_at(&server.uri(), "user", "pass")
+            .await
+            .unwrap();
+
+        let moers = watttime
+            .historic_moers(region, t0, Some(t0 + Duration::minutes(10)))
+            .await
+            .unwrap();
+        assert_eq!(moers.len(), 2);
+        assert_eq!(moers[0].start, t0);
+        assert_eq!(moers[0].duration, Duration::minutes(5));
+        assert!((moers[1].rate - 0.453_592_37).abs() < 1e-6);
+
+        let mut watttime = watttime.with_region(Some("PJM_DC".to_string()));
+        let forecast = watttime.forecast(region).await.unwrap();
+        assert_eq!(forecast.region, region);
+        assert_eq!(forecast.generated_at, t0);
+        assert!((forecast.data[&(t0 + Duration::minutes(5))] - 0.453_592_37).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn historic_forecasts() {
+        let server = stub_server().await;
+        Mock::given(method("GET"))
+            .and(path("/v3/forecast/historical"))
+            .and(query_param("region", "NEVP"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{
+                    "generated_at": "2021-03-01T00:00:00Z",
+                    "forecast": [{ "point_time": "2021-03-01T00:00:00Z", "value": 2204.62262 }],
+                }],
+                "meta": meta(),
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let t0 = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let mut watttime = WattTime::login_at(&server.uri(), "user", "pass")
+            .await
+            .unwrap();
+
+        // Two days are fetched a day at a time.
+        let forecasts = watttime
+            .historic_forecasts(GridRegion::NVENERGY, t0, t0 + Duration::days(2))
+            .await
+            .unwrap();
+        assert_eq!(forecasts.len(), 2);
+        assert!((forecasts[0].data[&t0] - 1.).abs() < 1e-9);
+    }
+}